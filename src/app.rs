@@ -1,10 +1,27 @@
+use crate::effects::{EffectParams, Effects, Insert, Param, Placement, ReverbParams, Value};
+use crate::engine;
+use crate::eq::EqParams;
+use crate::engine::Arrangement;
+use crate::engine::SwitchAt;
+use crate::filter::FilterMode;
+use crate::fm::{Algorithm, FmParams, OPERATOR_COUNT};
+use crate::import;
+use crate::lfo::{Lfo, LfoShape, LfoTarget};
 use crate::instr;
-use crate::instr::MEAS_COUNT;
-use crate::instr::NOTE_COUNT;
-use crate::instr::Note;
-use crate::synth;
-use crate::synth::Oscillator;
+use crate::instr::PatternNotes;
+use crate::instr::SongEntry;
+use crate::instr::Track;
+use crate::synth::Adsr;
+use crate::synth::{Oscillator, MAX_UNISON};
+use crate::synth::VoiceKind;
+use crate::perc::Drum;
+use crate::sampler::{Kit, Pad, Region, SampleRef, Sampler};
+use crate::wavetable::{Wavetable, TABLE_SIZE};
+use crate::synth::Patch;
+use crate::synth::ParamLocks;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
+use std::sync::Arc;
 use egui::RichText;
 use egui::Color32;
 
@@ -14,11 +31,21 @@ pub enum AudioState {
 }
 
 pub enum Messages {
-    Play(Vec<(Note, usize, usize)>),
+    Play(Vec<Vec<PatternNotes>>),
     Stop,
     Record,
     Tempo(u32),
-    Oscillator(Oscillator)
+    Patch(usize, Box<Patch>),
+    Sampler(usize, Sampler),
+    Kit(usize, Kit),
+    Wavetable(usize, Wavetable),
+    Effects(usize, Effects),
+    Reverb(ReverbParams),
+    Master(Vec<Insert>),
+    Arrangement(Arrangement),
+    QueuePattern(usize, SwitchAt),
+    Mute(usize, bool),
+    Solo(usize, bool),
 }
 
 
@@ -27,8 +54,27 @@ pub enum Messages {
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
 pub struct StepSequencer {
     
-    instr: instr::Instrument,
-    osc: Oscillator,
+    tracks: Vec<Track>,
+    track: usize,
+    pattern: usize,
+    song: Vec<SongEntry>,
+    song_mode: bool,
+    switch_at: SwitchAt,
+    stems_with_master: bool,
+    reverb: ReverbParams,
+    master: Vec<Insert>,
+    kit_path: String,
+    sfz_path: String,
+    wave_path: String,
+    //grid and oscillator saved before tracks and patches existed, moved onto the first track when loading
+    #[serde(skip_serializing, deserialize_with = "legacy")]
+    instr: Option<instr::Instrument>,
+    #[serde(skip_serializing, deserialize_with = "legacy")]
+    osc: Option<Oscillator>,
+    #[serde(skip)]
+    wave_frame: usize,
+    #[serde(skip)]
+    playing: Arc<AtomicUsize>,
     #[serde(skip)]
     tempo: u32,
     #[serde(skip)]
@@ -37,6 +83,8 @@ pub struct StepSequencer {
     tx: mpsc::Sender<Messages>,
     #[serde(skip)]
     recording: bool,
+    #[serde(skip)]
+    load_errors: Vec<String>,
 }
 
 impl Default for StepSequencer {
@@ -44,17 +92,26 @@ impl Default for StepSequencer {
         Self {
             // Example stuff:
             audio_state: AudioState::Off,
-            instr: instr::Instrument::default(),
+            tracks: vec![Track::default()],
+            track: 0,
+            pattern: 0,
+            song: vec![SongEntry::default()],
+            song_mode: false,
+            switch_at: SwitchAt::LoopEnd,
+            stems_with_master: true,
+            reverb: ReverbParams::default(),
+            master: vec![],
+            kit_path: "kit.ron".to_string(),
+            sfz_path: String::new(),
+            wave_path: String::new(),
+            instr: None,
+            osc: None,
+            wave_frame: 0,
             tempo: 60,
-            tx: {
-                let (tx, rx) = std::sync::mpsc::channel();
-                std::thread::spawn(move || {
-                    synth::process_audio(rx);
-                });
-                tx
-            },
+            playing: Arc::new(AtomicUsize::new(0)),
+            tx: mpsc::channel().0, //replaced by the audio thread's sender in `new`
             recording: false,
-            osc: Oscillator::Sin,
+            load_errors: vec![],
         }
     }
 }
@@ -68,14 +125,50 @@ impl StepSequencer {
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        let mut app: Self = cc.storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+        app.migrate();
+
+        // Started here rather than in `default`, which also runs for every load attempt.
+        let (tx, rx) = mpsc::channel();
+        let playing = app.playing.clone();
+        std::thread::spawn(move || {
+            engine::process_audio(rx, playing);
+        });
+        app.tx = tx;
+
+        // Only sample paths are saved, so decode the audio again.
+        for track in app.tracks.iter_mut() {
+            track.sampler.load_all();
+            track.kit.load_all();
         }
+        app
+    }
 
-        Default::default()
+    /// Moves state saved by older versions to where it lives now.
+    fn migrate(&mut self) {
+        if let Some(instr) = self.instr.take() {
+            self.tracks = vec![Track { patterns: vec![instr], ..Track::default() }];
+            self.song = vec![SongEntry::default()];
+            self.pattern = 0;
+            self.track = 0;
+        }
+        if let (Some(osc), Some(track)) = (self.osc.take(), self.tracks.first_mut()) {
+            track.patch.osc = osc;
+        }
+        Insert::renumber(&mut self.master);
+        for track in self.tracks.iter_mut() {
+            Insert::renumber(&mut track.effects.inserts);
+        }
     }
 }
 
+/// Reads a field that was saved as a plain value, before it became optional.
+fn legacy<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
 impl eframe::App for StepSequencer {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
@@ -86,16 +179,44 @@ impl eframe::App for StepSequencer {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         let Self { 
             audio_state,
-            instr, 
+            tracks,
+            track,
+            pattern,
+            song,
+            song_mode,
+            switch_at,
+            stems_with_master,
+            reverb,
+            master,
+            kit_path,
+            sfz_path,
+            wave_path,
+            instr: _,
+            osc: _,
+            wave_frame,
+            playing,
             tx,
             recording,
             tempo,
-            mut osc,
+            load_errors,
         } = self;
 
 
         ctx.request_repaint();
 
+        //samples decode in the background, hand them to the engine as they arrive
+        for (i, t) in tracks.iter_mut().enumerate() {
+            if t.sampler.poll(load_errors) {
+                tx.send(Messages::Sampler(i, t.sampler.clone())).unwrap();
+            }
+            if t.kit.poll(load_errors) {
+                tx.send(Messages::Kit(i, t.kit.clone())).unwrap();
+            }
+            if t.wavetable.poll(load_errors) {
+                tx.send(Messages::Wavetable(i, t.wavetable.clone())).unwrap();
+            }
+        }
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
             egui::menu::bar(ui, |ui| {
@@ -108,7 +229,7 @@ impl eframe::App for StepSequencer {
                         }
                         AudioState::Off => { 
                             *audio_state = AudioState::Playing;
-                            send_instrument_state(tx, instr);
+                            send_instrument_state(tx, get_setup_messages(tracks, *tempo, *reverb, master, get_arrangement(*song_mode, song, *pattern)));
                         }
                     }
                 }
@@ -116,23 +237,119 @@ impl eframe::App for StepSequencer {
                     *recording = true;
                     tx.send(Messages::Record).unwrap();
                     *audio_state = AudioState::Playing;
-                    send_instrument_state(tx, instr);
+                    send_instrument_state(tx, get_setup_messages(tracks, *tempo, *reverb, master, get_arrangement(*song_mode, song, *pattern)));
                 }
                 if ui.add(egui::DragValue::new(tempo)
                 .clamp_range(1..=240).prefix("Tempo: ").suffix(" bpm")).changed() {
                     tx.send(Messages::Tempo(*tempo)).unwrap();
                 };
-                let old_osc = osc;
-                egui::ComboBox::from_id_source("instrument")
-                .selected_text(format!("{:?}", osc))
+                ui.add_enabled(matches!(audio_state, AudioState::Off), egui::Checkbox::new(song_mode, "Song"));
+                if ui.button("Export").clicked() {
+                    let setup = get_setup_messages(tracks, *tempo, *reverb, master, get_arrangement(*song_mode, song, *pattern));
+                    std::thread::spawn(move || {
+                        engine::export_song(setup);
+                    });
+                }
+                if ui.button("Export stems").clicked() {
+                    let setup = get_setup_messages(tracks, *tempo, *reverb, master, get_arrangement(*song_mode, song, *pattern));
+                    let track_names = tracks.iter().map(|track| track.name.clone()).collect();
+                    let with_master = *stems_with_master;
+                    std::thread::spawn(move || {
+                        engine::export_stems(setup, track_names, with_master);
+                    });
+                }
+                ui.checkbox(stems_with_master, "with master");
+                ui.separator();
+                let playing_pattern = match audio_state {
+                    AudioState::Playing => Some(playing.load(Ordering::Relaxed)),
+                    AudioState::Off => None,
+                };
+                if draw_pattern_selector(ui, tracks, pattern, playing_pattern) && playing_pattern.is_some() {
+                    *song_mode = false;
+                    tx.send(Messages::QueuePattern(*pattern, *switch_at)).unwrap();
+                }
+                egui::ComboBox::from_id_source("switch_at")
+                .selected_text(switch_at.to_string())
                 .show_ui(ui, |ui| {
-                    ui.selectable_value( &mut osc, Oscillator::Sin, "Sin");
-                    ui.selectable_value( &mut osc, Oscillator::Sawtooth, "Sawtooth");
-                    ui.selectable_value( &mut osc, Oscillator::Triangle, "Triangle");
-                    ui.selectable_value( &mut osc, Oscillator::Pulse, "Pulse");
+                    ui.selectable_value(switch_at, SwitchAt::LoopEnd, SwitchAt::LoopEnd.to_string());
+                    ui.selectable_value(switch_at, SwitchAt::Bar, SwitchAt::Bar.to_string());
                 });
-                if osc != old_osc {
-                    tx.send(Messages::Oscillator(osc)).unwrap();
+            });
+        });
+
+        egui::TopBottomPanel::bottom("song_panel").show(ctx, |ui| {
+            ui.add_enabled_ui(matches!(audio_state, AudioState::Off), |ui| {
+                draw_song(ui, song, tracks[0].patterns.len());
+            });
+        });
+
+        egui::SidePanel::left("patch_panel").show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                draw_tracks(ui, tracks, track, tx, audio_state);
+                ui.separator();
+                let patch = &mut tracks[*track].patch;
+                let old_patch = *patch;
+                draw_patch(ui, patch);
+                if *patch != old_patch {
+                    tx.send(Messages::Patch(*track, Box::new(*patch))).unwrap();
+                }
+                if tracks[*track].patch.kind == VoiceKind::Sampler {
+                    ui.separator();
+                    let sampler = &mut tracks[*track].sampler;
+                    let old_sampler = sampler.clone();
+                    draw_sampler(ui, sampler, sfz_path, load_errors);
+                    if *sampler != old_sampler {
+                        tx.send(Messages::Sampler(*track, sampler.clone())).unwrap();
+                    }
+                }
+                if tracks[*track].patch.uses_wavetable() {
+                    ui.separator();
+                    let wavetable = &mut tracks[*track].wavetable;
+                    let old_frames = wavetable.frames.clone();
+                    draw_wavetable(ui, wavetable, wave_path, wave_frame);
+                    if wavetable.frames != old_frames {
+                        tx.send(Messages::Wavetable(*track, wavetable.clone())).unwrap();
+                    }
+                }
+                if tracks[*track].patch.kind == VoiceKind::Kit {
+                    ui.separator();
+                    let kit = &mut tracks[*track].kit;
+                    let old_kit = kit.clone();
+                    draw_kit(ui, kit, kit_path, load_errors);
+                    if *kit != old_kit {
+                        tx.send(Messages::Kit(*track, kit.clone())).unwrap();
+                    }
+                }
+                ui.separator();
+                let names: Vec<String> = tracks.iter().map(|t| t.name.clone()).collect();
+                let effects = &mut tracks[*track].effects;
+                let old_effects = effects.clone();
+                draw_effects(ui, effects, &names, *track);
+                if *effects != old_effects {
+                    tx.send(Messages::Effects(*track, effects.clone())).unwrap();
+                }
+                let old_reverb = *reverb;
+                egui::CollapsingHeader::new("Reverb bus").show(ui, |ui| {
+                    draw_reverb(ui, reverb);
+                });
+                if *reverb != old_reverb {
+                    tx.send(Messages::Reverb(*reverb)).unwrap();
+                }
+                let old_master = master.clone();
+                egui::CollapsingHeader::new("Master inserts").show(ui, |ui| {
+                    draw_inserts(ui, master, &Placement::default());
+                });
+                if *master != old_master {
+                    tx.send(Messages::Master(master.clone())).unwrap();
+                }
+                if !load_errors.is_empty() {
+                    ui.separator();
+                    for error in load_errors.iter() {
+                        ui.colored_label(Color32::RED, error);
+                    }
+                    if ui.button("Dismiss").clicked() {
+                        load_errors.clear();
+                    }
                 }
             });
         });
@@ -140,39 +357,740 @@ impl eframe::App for StepSequencer {
         egui::CentralPanel::default().show(ctx, |ui| {
             
             //Add instruments
-            draw_instrument(ui, instr, audio_state);
+            let Track { patterns, patch, kit, .. } = &mut tracks[*track];
+            draw_instrument(ui, &mut patterns[*pattern], patch, kit, audio_state);
 
             egui::warn_if_debug_build(ui);
         });
     }
 }
 
-fn draw_instrument(ui: &mut egui::Ui, instr: &mut instr::Instrument, audio_state: &AudioState) {
+/// Returns whether a pattern was picked, while playing the picked pattern is queued.
+fn draw_pattern_selector(
+    ui: &mut egui::Ui, 
+    tracks: &mut [Track], 
+    pattern: &mut usize, 
+    playing_pattern: Option<usize>,
+) -> bool {
+    let pattern_count = tracks[0].patterns.len();
+    let mut picked = false;
+    for i in 0..pattern_count {
+        let text = RichText::new(instr::pattern_name(i));
+        let text = if playing_pattern == Some(i) {text.color(Color32::DARK_GREEN).strong()} else {text};
+        picked |= ui.selectable_value(pattern, i, text).clicked();
+    }
+    if ui.add_enabled(
+        pattern_count < instr::MAX_PATTERNS && playing_pattern.is_none(), 
+        egui::Button::new("+"),
+    ).clicked() {
+        for track in tracks.iter_mut() {
+            track.patterns.push(instr::Instrument::default());
+        }
+        *pattern = pattern_count;
+    }
+    picked
+}
+
+fn draw_tracks(
+    ui: &mut egui::Ui, 
+    tracks: &mut Vec<Track>, 
+    track: &mut usize, 
+    tx: &mpsc::Sender<Messages>, 
+    audio_state: &AudioState,
+) {
+    ui.heading("Tracks");
+    for (i, t) in tracks.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            if ui.toggle_value(&mut t.mute, "M").changed() {
+                tx.send(Messages::Mute(i, t.mute)).unwrap();
+            }
+            if ui.toggle_value(&mut t.solo, "S").changed() {
+                tx.send(Messages::Solo(i, t.solo)).unwrap();
+            }
+            ui.selectable_value(track, i, t.name.as_str());
+        });
+    }
+    ui.add_enabled_ui(matches!(audio_state, AudioState::Off), |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("+").clicked() {
+                let pattern_count = tracks[0].patterns.len();
+                tracks.push(Track::new(format!("Track {}", tracks.len() + 1), pattern_count));
+                *track = tracks.len() - 1;
+            }
+            if ui.add_enabled(tracks.len() > 1, egui::Button::new("🗑")).clicked() {
+                let removed = *track;
+                tracks.remove(removed);
+                *track = track.saturating_sub(1);
+                //keep sidechains pointing at the same tracks
+                let compressors = tracks.iter_mut()
+                    .flat_map(|t| t.effects.inserts.iter_mut())
+                    .filter_map(|insert| match &mut insert.effect {
+                        EffectParams::Compressor(compressor) => Some(compressor),
+                        _ => None,
+                    });
+                for compressor in compressors {
+                    compressor.sidechain = match compressor.sidechain {
+                        Some(source) if source == removed => None,
+                        Some(source) if source > removed => Some(source - 1),
+                        sidechain => sidechain,
+                    };
+                }
+            }
+        });
+    });
+    ui.text_edit_singleline(&mut tracks[*track].name);
+}
+
+fn draw_song(ui: &mut egui::Ui, song: &mut Vec<SongEntry>, pattern_count: usize) {
+    ui.horizontal_wrapped(|ui| {
+        ui.label("Song:");
+        let mut removed = None;
+        for (i, entry) in song.iter_mut().enumerate() {
+            egui::Frame::group(ui.style()).show(ui, |ui| {
+                egui::ComboBox::from_id_source(("song_entry", i))
+                .width(40.0)
+                .selected_text(instr::pattern_name(entry.pattern))
+                .show_ui(ui, |ui| {
+                    for pattern in 0..pattern_count {
+                        ui.selectable_value(&mut entry.pattern, pattern, instr::pattern_name(pattern));
+                    }
+                });
+                ui.add(egui::DragValue::new(&mut entry.repeats).clamp_range(1..=64).prefix("x"));
+                if ui.small_button("🗑").clicked() {
+                    removed = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed {
+            song.remove(i);
+        }
+        if ui.button("+").clicked() {
+            song.push(song.last().copied().unwrap_or_default());
+        }
+    });
+}
+
+fn get_arrangement(song_mode: bool, song: &[SongEntry], pattern: usize) -> Arrangement {
+    if song_mode {Arrangement::Song(song.to_vec())}
+    else {Arrangement::Loop(pattern)}
+}
+
+fn draw_patch(ui: &mut egui::Ui, patch: &mut Patch) {
+    ui.heading("Patch");
+    egui::ComboBox::from_id_source("voice_kind")
+    .selected_text(patch.kind.to_string())
+    .show_ui(ui, |ui| {
+        for kind in [
+            VoiceKind::Synth, 
+            VoiceKind::Fm,
+            VoiceKind::Drum(Drum::Kick), 
+            VoiceKind::Drum(Drum::Snare), 
+            VoiceKind::Drum(Drum::Hat),
+            VoiceKind::Sampler,
+            VoiceKind::Kit,
+        ] {
+            ui.selectable_value(&mut patch.kind, kind, kind.to_string());
+        }
+    });
+    ui.add(egui::Slider::new(&mut patch.volume, 0.0..=1.0).text("Volume"));
+    ui.add(egui::Slider::new(&mut patch.pan, -1.0..=1.0).text("Pan"));
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut patch.mono, "Mono");
+        ui.add_enabled(patch.mono, egui::Checkbox::new(&mut patch.legato, "Legato"));
+    });
+    ui.add_enabled(patch.mono, egui::Slider::new(&mut patch.glide, 0.0..=1.0).text("Glide").suffix(" s"));
+    ui.separator();
+    match patch.kind {
+        VoiceKind::Synth => {
+            draw_oscillator_combo(ui, "instrument", &mut patch.osc);
+            ui.horizontal(|ui| {
+                draw_oscillator_combo(ui, "instrument_osc2", &mut patch.osc2);
+                ui.add(egui::DragValue::new(&mut patch.osc2_pitch).clamp_range(-24.0..=24.0).speed(0.05).suffix(" st"));
+            });
+            ui.add(egui::Slider::new(&mut patch.osc_mix, 0.0..=1.0).text("Osc mix"));
+            if patch.uses_wavetable() {
+                let wave = &mut patch.wave;
+                ui.add(egui::Slider::new(&mut wave.position, 0.0..=1.0).text("Position"));
+                ui.add(egui::Slider::new(&mut wave.env_amount, -1.0..=1.0).text("Position env"));
+                ui.add_enabled_ui(wave.env_amount != 0.0, |ui| {
+                    draw_adsr(ui, &mut wave.envelope);
+                });
+            }
+            if patch.osc == Oscillator::Pulse || patch.osc2 == Oscillator::Pulse {
+                let pulse = &mut patch.pulse;
+                ui.add(egui::Slider::new(&mut pulse.width, 0.02..=0.98).text("Pulse width"));
+                ui.add(egui::Slider::new(&mut pulse.env_amount, -0.5..=0.5).text("Width env"));
+                ui.add_enabled_ui(pulse.env_amount != 0.0, |ui| {
+                    draw_adsr(ui, &mut pulse.envelope);
+                });
+            }
+            ui.label("Unison");
+            let unison = &mut patch.unison;
+            ui.add(egui::Slider::new(&mut unison.voices, 1..=MAX_UNISON).text("Voices"));
+            ui.add_enabled_ui(unison.voices > 1, |ui| {
+                ui.add(egui::Slider::new(&mut unison.detune, 0.0..=100.0).text("Detune").suffix(" ct"));
+                ui.add(egui::Slider::new(&mut unison.width, 0.0..=1.0).text("Width"));
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(&mut patch.sub.level, 0.0..=1.0).text("Sub"));
+                ui.add(egui::DragValue::new(&mut patch.sub.octaves).clamp_range(1..=2).prefix("-").suffix(" oct"));
+            });
+            ui.label("Envelope");
+            draw_adsr(ui, &mut patch.envelope);
+        }
+        VoiceKind::Fm => {
+            draw_fm(ui, &mut patch.fm);
+        }
+        VoiceKind::Sampler => {
+            ui.label("Envelope");
+            draw_adsr(ui, &mut patch.envelope);
+        }
+        VoiceKind::Drum(drum) => {
+            let tone = match drum {
+                Drum::Kick => "Click",
+                Drum::Snare => "Body",
+                Drum::Hat => "Brightness",
+            };
+            ui.add(egui::Slider::new(&mut patch.drum.decay, 0.01..=2.0).logarithmic(true).text("Decay").suffix(" s"));
+            ui.add(egui::Slider::new(&mut patch.drum.tone, 0.0..=1.0).text(tone));
+            if drum == Drum::Kick {
+                ui.add(egui::Slider::new(&mut patch.drum.sweep, 0.0..=4.0).text("Sweep").suffix(" oct"));
+            }
+        }
+        VoiceKind::Kit => {}
+    }
+    ui.separator();
+    ui.label("Filter");
+    let filter = &mut patch.filter;
+    egui::ComboBox::from_id_source("filter_mode")
+    .selected_text(filter.mode.to_string())
+    .show_ui(ui, |ui| {
+        for mode in [FilterMode::Off, FilterMode::LowPass, FilterMode::HighPass, FilterMode::BandPass] {
+            ui.selectable_value(&mut filter.mode, mode, mode.to_string());
+        }
+    });
+    ui.add_enabled_ui(filter.mode != FilterMode::Off, |ui| {
+        ui.add(egui::Slider::new(&mut filter.cutoff, 20.0..=20000.0).logarithmic(true).text("Cutoff").suffix(" Hz"));
+        ui.add(egui::Slider::new(&mut filter.resonance, 0.0..=1.0).text("Resonance"));
+        ui.add(egui::Slider::new(&mut filter.env_amount, -8.0..=8.0).text("Env amount").suffix(" oct"));
+        draw_adsr(ui, &mut filter.envelope);
+    });
+    for (i, lfo) in patch.lfos.iter_mut().enumerate() {
+        ui.separator();
+        ui.label(format!("LFO {}", i + 1));
+        draw_lfo(ui, i, lfo);
+    }
+}
+
+fn draw_fm(ui: &mut egui::Ui, fm: &mut FmParams) {
+    ui.horizontal(|ui| {
+        ui.add(egui::DragValue::new(&mut fm.operators).clamp_range(2..=OPERATOR_COUNT).suffix(" operators"));
+        egui::ComboBox::from_id_source("fm_algorithm")
+        .selected_text(fm.algorithm.to_string())
+        .show_ui(ui, |ui| {
+            for algorithm in [Algorithm::Stack, Algorithm::Pairs, Algorithm::Fan, Algorithm::Additive] {
+                ui.selectable_value(&mut fm.algorithm, algorithm, algorithm.to_string());
+            }
+        });
+    });
+    ui.add(egui::Slider::new(&mut fm.feedback, 0.0..=1.0).text("Feedback"));
+    for (i, op) in fm.ops.iter_mut().enumerate().take(fm.operators) {
+        egui::CollapsingHeader::new(format!("Operator {}", i + 1)).default_open(i < 2).show(ui, |ui| {
+            ui.add(egui::DragValue::new(&mut op.ratio).clamp_range(0.125..=16.0).speed(0.01).prefix("Ratio: "));
+            ui.add(egui::Slider::new(&mut op.level, 0.0..=1.0).text("Level"));
+            draw_adsr(ui, &mut op.envelope);
+        });
+    }
+}
+
+/// `names` lists every track for the sidechain choice, `track` being the one edited.
+fn draw_effects(ui: &mut egui::Ui, effects: &mut Effects, names: &[String], track: usize) {
+    ui.heading("Effects");
+    ui.push_id(("track_inserts", track), |ui| {
+        draw_inserts(ui, &mut effects.inserts, &Placement { names, track: Some(track) });
+    });
+    ui.add(egui::Slider::new(&mut effects.reverb_send, 0.0..=1.0).text("Reverb send"));
+}
+
+/// An insert chain in processing order.
+fn draw_inserts(ui: &mut egui::Ui, inserts: &mut Vec<Insert>, placement: &Placement<'_>) {
+    let mut moved = None;
+    let mut removed = None;
+    let count = inserts.len();
+    for (i, insert) in inserts.iter_mut().enumerate() {
+        ui.push_id(("insert", insert.id), |ui| {
+            let id = ui.make_persistent_id("insert");
+            egui::collapsing_header::CollapsingState::load_with_default_open(ui.ctx(), id, false)
+            .show_header(ui, |ui| {
+                let mut on = !insert.bypass;
+                if ui.checkbox(&mut on, insert.effect.name()).changed() {
+                    insert.bypass = !on;
+                }
+                if ui.add_enabled(i > 0, egui::Button::new("⏶").small()).clicked() {
+                    moved = Some((i, i - 1));
+                }
+                if ui.add_enabled(i + 1 < count, egui::Button::new("⏷").small()).clicked() {
+                    moved = Some((i, i + 1));
+                }
+                if ui.small_button("🗑").clicked() {
+                    removed = Some(i);
+                }
+            })
+            .body(|ui| {
+                ui.add_enabled_ui(!insert.bypass, |ui| {
+                    draw_insert(ui, &mut insert.effect, placement);
+                });
+            });
+        });
+    }
+    if let Some((from, to)) = moved {
+        inserts.swap(from, to);
+    }
+    if let Some(i) = removed {
+        inserts.remove(i);
+    }
+    ui.menu_button("Add insert", |ui| {
+        for effect in EffectParams::defaults() {
+            if ui.button(effect.name()).clicked() {
+                inserts.push(Insert::new(inserts, effect));
+                ui.close_menu();
+            }
+        }
+    });
+}
+
+/// Controls for every setting of an effect, settings sharing a line are drawn compactly.
+fn draw_insert(ui: &mut egui::Ui, effect: &mut EffectParams, placement: &Placement<'_>) {
+    if let EffectParams::Eq(eq) = effect {
+        draw_eq_curve(ui, eq);
+    }
+    let mut params = effect.params(placement).into_iter().enumerate().peekable();
+    while let Some(first) = params.next() {
+        let mut line = vec![first];
+        while let Some(next) = params.next_if(|(_, param)| param.same_line) {
+            line.push(next);
+        }
+        if line.len() == 1 {
+            let (i, param) = line.remove(0);
+            draw_param(ui, i, param, false);
+        }
+        else {
+            ui.horizontal(|ui| {
+                for (i, param) in line {
+                    draw_param(ui, i, param, true);
+                }
+            });
+        }
+    }
+}
+
+fn draw_param(ui: &mut egui::Ui, index: usize, param: Param<'_>, compact: bool) {
+    ui.push_id(index, |ui| {
+        ui.add_enabled_ui(param.enabled, |ui| {
+            match param.value {
+                Value::Float { value, range, unit, logarithmic } if compact => {
+                    let speed = if logarithmic {*value * 0.01} else {(range.end() - range.start()) * 0.002};
+                    let prefix = if param.name.is_empty() {String::new()} else {format!("{}: ", param.name)};
+                    ui.add(egui::DragValue::new(value).clamp_range(range).speed(speed).prefix(prefix).suffix(unit));
+                }
+                Value::Float { value, range, unit, logarithmic } => {
+                    ui.add(egui::Slider::new(value, range).logarithmic(logarithmic).text(param.name).suffix(unit));
+                }
+                Value::Int { value, range } => {
+                    ui.add(egui::Slider::new(value, range).text(param.name));
+                }
+                Value::Toggle(value) => {
+                    ui.checkbox(value, param.name);
+                }
+                Value::Choice(mut choice) => {
+                    let selected = choice.selected.and_then(|i| choice.labels.get(i)).cloned().unwrap_or_default();
+                    let combo = egui::ComboBox::from_label(param.name).selected_text(selected);
+                    let combo = if compact {combo.width(80.0)} else {combo};
+                    let mut picked = None;
+                    combo.show_ui(ui, |ui| {
+                        for (i, label) in choice.labels.iter().enumerate() {
+                            if ui.selectable_label(choice.selected == Some(i), label).clicked() {
+                                picked = Some(i);
+                            }
+                        }
+                    });
+                    if let Some(i) = picked {
+                        choice.select(i);
+                    }
+                }
+            }
+        });
+    });
+}
+
+/// Summed response of the enabled bands.
+fn draw_eq_curve(ui: &mut egui::Ui, eq: &EqParams) {
+    //log frequency from 20 Hz to 20 kHz across, ±24 dB up and down
+    let (response, painter) = ui.allocate_painter(egui::vec2(256.0, 96.0), egui::Sense::hover());
+    let rect = response.rect;
+    let to_y = |db: f32| rect.center().y - db.clamp(-24.0, 24.0) / 24.0 * rect.height() / 2.0;
+    painter.rect_filled(rect, 0.0, Color32::WHITE);
+    painter.hline(rect.x_range(), rect.center().y, egui::Stroke::new(1.0, Color32::LIGHT_GRAY));
+    for decade in [100.0f32, 1000.0, 10000.0] {
+        let x = rect.left() + rect.width() * (decade / 20.0).log10() / 3.0;
+        painter.vline(x, rect.y_range(), egui::Stroke::new(1.0, Color32::LIGHT_GRAY));
+    }
+    let points = (0..=rect.width() as usize).map(|i| {
+        let x = rect.left() + i as f32;
+        let freq = 20.0 * 10.0f32.powf(3.0 * i as f32 / rect.width());
+        egui::pos2(x, to_y(eq.response(freq)))
+    }).collect();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, Color32::DARK_BLUE)));
+}
+
+fn draw_reverb(ui: &mut egui::Ui, reverb: &mut ReverbParams) {
+    ui.checkbox(&mut reverb.enabled, "On");
+    ui.add_enabled_ui(reverb.enabled, |ui| {
+        ui.add(egui::Slider::new(&mut reverb.size, 0.0..=1.0).text("Size"));
+        ui.add(egui::Slider::new(&mut reverb.damping, 0.0..=1.0).text("Damping"));
+        ui.add(egui::Slider::new(&mut reverb.mix, 0.0..=1.0).text("Mix"));
+    });
+}
+
+fn draw_lfo(ui: &mut egui::Ui, index: usize, lfo: &mut Lfo) {
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_source(("lfo_shape", index))
+        .selected_text(lfo.shape.to_string())
+        .show_ui(ui, |ui| {
+            for shape in [LfoShape::Sine, LfoShape::Triangle, LfoShape::Square, LfoShape::SampleAndHold] {
+                ui.selectable_value(&mut lfo.shape, shape, shape.to_string());
+            }
+        });
+        egui::ComboBox::from_id_source(("lfo_target", index))
+        .selected_text(lfo.target.to_string())
+        .show_ui(ui, |ui| {
+            for target in [
+                LfoTarget::Pitch, 
+                LfoTarget::Cutoff, 
+                LfoTarget::Amplitude, 
+                LfoTarget::Pan, 
+                LfoTarget::WavePosition, 
+                LfoTarget::PulseWidth,
+            ] {
+                if ui.selectable_value(&mut lfo.target, target, target.to_string()).changed() {
+                    lfo.depth = lfo.depth.min(target.max_depth());
+                }
+            }
+        });
+    });
+    ui.add(egui::Slider::new(&mut lfo.depth, 0.0..=lfo.target.max_depth()).text("Depth").suffix(lfo.target.unit()));
+    ui.checkbox(&mut lfo.sync, "Tempo sync");
+    if lfo.sync {
+        ui.add(egui::Slider::new(&mut lfo.steps, 1..=64).text("Steps per cycle"));
+    }
+    else {
+        ui.add(egui::Slider::new(&mut lfo.rate, 0.05..=40.0).logarithmic(true).text("Rate").suffix(" Hz"));
+    }
+}
+
+fn draw_sampler(ui: &mut egui::Ui, sampler: &mut Sampler, sfz_path: &mut String, load_errors: &mut Vec<String>) {
+    ui.label("Samples");
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(sfz_path).hint_text("SFZ file").desired_width(140.0));
+        if ui.button("Import").clicked() {
+            match import::import_sfz(sfz_path) {
+                Ok(imported) => *sampler = imported,
+                Err(error) => load_errors.push(error),
+            }
+        }
+    });
+    let mut removed = None;
+    for (i, region) in sampler.regions.iter_mut().enumerate() {
+        ui.push_id(("region", i), |ui| {
+            egui::Frame::group(ui.style()).show(ui, |ui| {
+                draw_region(ui, region);
+                if ui.small_button("🗑").clicked() {
+                    removed = Some(i);
+                }
+            });
+        });
+    }
+    if let Some(i) = removed {
+        sampler.regions.remove(i);
+    }
+    if ui.button("Add sample").clicked() {
+        sampler.regions.push(Region::default());
+    }
+}
+
+/// Path field and load button, the decoded audio arrives later through `poll`.
+fn draw_sample_file(ui: &mut egui::Ui, sample: &mut SampleRef) {
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut sample.path).hint_text("WAV, FLAC or OGG file").desired_width(140.0));
+        let text = if sample.is_loading() {"Loading..."} else if sample.data.is_some() {"Reload"} else {"Load"};
+        if ui.add_enabled(!sample.is_loading(), egui::Button::new(text)).clicked() {
+            sample.load();
+        }
+    });
+}
+
+fn draw_region(ui: &mut egui::Ui, region: &mut Region) {
+    draw_sample_file(ui, &mut region.sample);
+    ui.horizontal(|ui| {
+        ui.add(egui::DragValue::new(&mut region.root).clamp_range(0..=127)
+        .custom_formatter(|n, _| key_text(n)).prefix("Root: "));
+        ui.checkbox(&mut region.pitched, "Pitched");
+        ui.checkbox(&mut region.one_shot, "One-shot");
+    });
+    ui.horizontal(|ui| {
+        ui.label("Keys");
+        ui.add(egui::DragValue::new(&mut region.lo_key).clamp_range(0..=region.hi_key)
+        .custom_formatter(|n, _| key_text(n)));
+        ui.add(egui::DragValue::new(&mut region.hi_key).clamp_range(region.lo_key..=127)
+        .custom_formatter(|n, _| key_text(n)));
+    });
+    ui.horizontal(|ui| {
+        ui.label("Velocities");
+        ui.add(egui::DragValue::new(&mut region.lo_vel).clamp_range(0..=region.hi_vel));
+        ui.add(egui::DragValue::new(&mut region.hi_vel).clamp_range(region.lo_vel..=127));
+    });
+}
+
+fn draw_kit(ui: &mut egui::Ui, kit: &mut Kit, kit_path: &mut String, load_errors: &mut Vec<String>) {
+    ui.label("Kit");
+    ui.add(egui::TextEdit::singleline(&mut kit.name).hint_text("Kit name"));
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(kit_path).hint_text("Kit file or Hydrogen drumkit.xml").desired_width(140.0));
+        if ui.button("Load").clicked() {
+            let loaded = if kit_path.ends_with(".xml") {import::import_hydrogen(kit_path)} else {Kit::load(kit_path)};
+            match loaded {
+                Ok(loaded) => *kit = loaded,
+                Err(error) => load_errors.push(error),
+            }
+        }
+        if ui.button("Save").clicked() {
+            if let Err(error) = kit.save(kit_path) {
+                load_errors.push(error);
+            }
+        }
+    });
+    let mut removed = None;
+    for (i, pad) in kit.pads.iter_mut().enumerate() {
+        ui.push_id(("pad", i), |ui| {
+            egui::Frame::group(ui.style()).show(ui, |ui| {
+                draw_pad(ui, pad);
+                if ui.small_button("🗑").clicked() {
+                    removed = Some(i);
+                }
+            });
+        });
+    }
+    if let Some(i) = removed {
+        kit.pads.remove(i);
+    }
+    if ui.add_enabled(kit.pads.len() < instr::NOTE_COUNT, egui::Button::new("Add pad")).clicked() {
+        kit.pads.push(Pad { name: format!("Pad {}", kit.pads.len() + 1), ..Pad::default() });
+    }
+}
+
+fn draw_pad(ui: &mut egui::Ui, pad: &mut Pad) {
+    ui.add(egui::TextEdit::singleline(&mut pad.name).desired_width(140.0));
+    draw_sample_file(ui, &mut pad.sample);
+    ui.add(egui::Slider::new(&mut pad.volume, 0.0..=1.0).text("Volume"));
+    ui.add(egui::Slider::new(&mut pad.pan, -1.0..=1.0).text("Pan"));
+    ui.horizontal(|ui| {
+        ui.add(egui::DragValue::new(&mut pad.tune).clamp_range(-24.0..=24.0).speed(0.1).prefix("Tune: ").suffix(" st"));
+        ui.add(egui::DragValue::new(&mut pad.choke).clamp_range(0..=8)
+        .custom_formatter(|n, _| if n == 0.0 {"none".to_string()} else {format!("{}", n)}).prefix("Choke: "));
+    });
+}
+
+/// Frame picker, file loading and a small editor to draw the picked frame with the mouse.
+fn draw_wavetable(
+    ui: &mut egui::Ui, 
+    wavetable: &mut Wavetable, 
+    wave_path: &mut String, 
+    frame: &mut usize, 
+) {
+    ui.label("Wavetable");
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(wave_path).hint_text("WAV file").desired_width(140.0));
+        let text = if wavetable.is_loading() {"Loading..."} else {"Load"};
+        if ui.add_enabled(!wavetable.is_loading(), egui::Button::new(text)).clicked() {
+            wavetable.load(wave_path);
+        }
+    });
+    if wavetable.frames.is_empty() {
+        *wavetable = Wavetable::default();
+    }
+    *frame = (*frame).min(wavetable.frames.len() - 1);
+    ui.horizontal(|ui| {
+        ui.add(egui::DragValue::new(frame).clamp_range(0..=wavetable.frames.len() - 1)
+        .prefix("Frame: ").suffix(format!(" of {}", wavetable.frames.len())));
+        if ui.button("+").clicked() {
+            let copy = wavetable.frames[*frame].clone();
+            wavetable.frames.insert(*frame + 1, copy);
+            *frame += 1;
+        }
+        if ui.add_enabled(wavetable.frames.len() > 1, egui::Button::new("🗑")).clicked() {
+            wavetable.frames.remove(*frame);
+            *frame = frame.saturating_sub(1);
+        }
+        if ui.button("Reset").clicked() {
+            *wavetable = Wavetable::default();
+            *frame = 0;
+        }
+    });
+
+    let samples = &mut wavetable.frames[*frame];
+    samples.resize(TABLE_SIZE, 0.0);
+    let (response, painter) = ui.allocate_painter(egui::vec2(256.0, 96.0), egui::Sense::drag());
+    let rect = response.rect;
+    let to_index = |x: f32| (((x - rect.left()) / rect.width() * TABLE_SIZE as f32) as usize).min(TABLE_SIZE - 1);
+    let to_value = |y: f32| (1.0 - 2.0 * (y - rect.top()) / rect.height()).clamp(-1.0, 1.0);
+    if let Some(pos) = response.interact_pointer_pos() {
+        //fill in between pointer positions so fast strokes leave no gaps
+        let last = pos - response.drag_delta();
+        let (from, to) = (to_index(last.x.clamp(rect.left(), rect.right())), to_index(pos.x.clamp(rect.left(), rect.right())));
+        let (start, end) = (to_value(last.y), to_value(pos.y));
+        for (i, sample) in samples.iter_mut().enumerate().take(from.max(to) + 1).skip(from.min(to)) {
+            let t = if from == to {1.0} else {(i as f32 - from as f32) / (to as f32 - from as f32)};
+            *sample = start + (end - start) * t;
+        }
+    }
+    painter.rect_filled(rect, 0.0, Color32::WHITE);
+    painter.hline(rect.x_range(), rect.center().y, egui::Stroke::new(1.0, Color32::LIGHT_GRAY));
+    let points = samples.iter().enumerate().map(|(i, value)| {
+        egui::pos2(
+            rect.left() + rect.width() * i as f32 / (TABLE_SIZE - 1) as f32, 
+            rect.center().y - value * rect.height() / 2.0,
+        )
+    }).collect();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, Color32::DARK_BLUE)));
+}
+
+/// Note name for keys on the grid, the plain note number below it.
+fn key_text(note_num: f64) -> String {
+    if note_num >= 21.0 {instr::note_num_to_str(note_num as usize)}
+    else {format!("{}", note_num)}
+}
+
+fn draw_adsr(ui: &mut egui::Ui, adsr: &mut Adsr) {
+    ui.add(egui::Slider::new(&mut adsr.attack, 0.0..=2.0).text("Attack").suffix(" s"));
+    ui.add(egui::Slider::new(&mut adsr.decay, 0.0..=2.0).text("Decay").suffix(" s"));
+    ui.add(egui::Slider::new(&mut adsr.sustain, 0.0..=1.0).text("Sustain"));
+    ui.add(egui::Slider::new(&mut adsr.release, 0.0..=4.0).text("Release").suffix(" s"));
+}
+
+fn draw_oscillator_combo(ui: &mut egui::Ui, id_source: impl std::hash::Hash, osc: &mut Oscillator) {
+    egui::ComboBox::from_id_source(id_source)
+    .selected_text(format!("{:?}", osc))
+    .show_ui(ui, |ui| {
+        ui.selectable_value(osc, Oscillator::Sin, "Sin");
+        ui.selectable_value(osc, Oscillator::Sawtooth, "Sawtooth");
+        ui.selectable_value(osc, Oscillator::Triangle, "Triangle");
+        ui.selectable_value(osc, Oscillator::Pulse, "Pulse");
+        ui.selectable_value(osc, Oscillator::WhiteNoise, "WhiteNoise");
+        ui.selectable_value(osc, Oscillator::PinkNoise, "PinkNoise");
+        ui.selectable_value(osc, Oscillator::Wavetable, "Wavetable");
+    });
+}
+
+fn draw_instrument(ui: &mut egui::Ui, instr: &mut instr::Instrument, patch: &Patch, kit: &Kit, audio_state: &AudioState) {
     egui::Frame::group(ui.style())
     .fill(egui::Color32::LIGHT_BLUE)
     .show(ui, |ui| {
             egui::ScrollArea::both().show(ui, |ui| {
                 ui.style_mut().spacing.item_spacing = egui::vec2(0.0, 20.0);
-                draw_note_grid(ui, instr, audio_state);                
+                draw_note_grid(ui, instr, patch, kit, audio_state);
             });
     });
 }
 
-fn draw_note_grid(ui: &mut egui::Ui, instr: &mut instr::Instrument, audio_state: &AudioState) {
+fn draw_note_grid(ui: &mut egui::Ui, instr: &mut instr::Instrument, patch: &Patch, kit: &Kit, audio_state: &AudioState) {
+    //kits get one row per pad, in kit order
+    let rows: Vec<(usize, String)> = if patch.kind == VoiceKind::Kit {
+        kit.pads.iter().take(instr::NOTE_COUNT).map(|pad| pad.name.clone()).enumerate().collect()
+    }
+    else {
+        (0..instr::NOTE_COUNT).rev().map(|note_num| (note_num, instr::note_num_to_str(note_num+21))).collect()
+    };
     egui::Grid::new("Instrument1").striped(true).show(ui, |ui| {
-        for note_num in (0..instr::NOTE_COUNT).rev() {
-            ui.label(instr::note_num_to_str(note_num+21));
+        for (note_num, label) in rows {
+            ui.label(label);
             for meas_num in 0..instr::MEAS_COUNT {
-                if ui.add_enabled(if let AudioState::Playing = audio_state {false} else {true}, 
-                egui::Button::new(get_note_button_text(instr, note_num, meas_num))).clicked() {
+                let response = ui.add_enabled(!matches!(audio_state, AudioState::Playing), 
+                egui::Button::new(get_note_button_text(instr, note_num, meas_num)));
+                if response.clicked() {
                     handle_note_button_click(instr, note_num, meas_num);
                 }
+                if let Some(start_ind) = instr.get_note(note_num, meas_num).starts_at {
+                    response.context_menu(|ui| {
+                        draw_note_menu(ui, instr.get_note_mut(note_num, start_ind), patch);
+                    });
+                }
             }
             ui.end_row();
         }
     });
 }
 
+fn draw_note_menu(ui: &mut egui::Ui, note: &mut instr::Note, patch: &Patch) {
+    ui.add(egui::Slider::new(&mut note.velocity, 0.0..=1.0).text("Velocity"));
+    ui.add(egui::DragValue::new(&mut note.ratchet)
+    .clamp_range(1..=instr::MAX_RATCHET).prefix("Ratchet: ").suffix("x"));
+    ui.add_enabled(note.ratchet > 1, egui::Checkbox::new(&mut note.ratchet_ramp, "Velocity ramp"));
+    ui.add_enabled(patch.mono, egui::Checkbox::new(&mut note.slide, "Slide"));
+    ui.separator();
+    draw_locks(ui, &mut note.locks, patch);
+}
+
+fn draw_locks(ui: &mut egui::Ui, locks: &mut ParamLocks, patch: &Patch) {
+    draw_lock(ui, "Oscillator", &mut locks.osc, patch.osc, |ui, osc| {
+        draw_oscillator_combo(ui, "lock_osc", osc);
+    });
+    draw_lock(ui, "Volume", &mut locks.volume, patch.volume, |ui, volume| {
+        ui.add(egui::Slider::new(volume, 0.0..=1.0));
+    });
+    draw_lock(ui, "Pan", &mut locks.pan, patch.pan, |ui, pan| {
+        ui.add(egui::Slider::new(pan, -1.0..=1.0));
+    });
+    draw_lock(ui, "Cutoff", &mut locks.cutoff, patch.filter.cutoff, |ui, cutoff| {
+        ui.add(egui::Slider::new(cutoff, 20.0..=20000.0).logarithmic(true).suffix(" Hz"));
+    });
+    if patch.uses_wavetable() {
+        draw_lock(ui, "Position", &mut locks.position, patch.wave.position, |ui, position| {
+            ui.add(egui::Slider::new(position, 0.0..=1.0));
+        });
+    }
+    draw_lock(ui, "Attack", &mut locks.attack, patch.envelope.attack, |ui, attack| {
+        ui.add(egui::Slider::new(attack, 0.0..=2.0).suffix(" s"));
+    });
+    draw_lock(ui, "Decay", &mut locks.decay, patch.envelope.decay, |ui, decay| {
+        ui.add(egui::Slider::new(decay, 0.0..=2.0).suffix(" s"));
+    });
+    draw_lock(ui, "Sustain", &mut locks.sustain, patch.envelope.sustain, |ui, sustain| {
+        ui.add(egui::Slider::new(sustain, 0.0..=1.0));
+    });
+    draw_lock(ui, "Release", &mut locks.release, patch.envelope.release, |ui, release| {
+        ui.add(egui::Slider::new(release, 0.0..=4.0).suffix(" s"));
+    });
+}
+
+/// Checkbox that locks a parameter to the note, starting from the track's current value.
+fn draw_lock<T: Copy>(
+    ui: &mut egui::Ui, 
+    label: &str, 
+    lock: &mut Option<T>, 
+    track_value: T, 
+    add_contents: impl FnOnce(&mut egui::Ui, &mut T),
+) {
+    ui.horizontal(|ui| {
+        let mut locked = lock.is_some();
+        if ui.checkbox(&mut locked, label).changed() {
+            *lock = if locked {Some(track_value)} else {None};
+        }
+        if let Some(value) = lock {
+            add_contents(ui, value);
+        }
+    });
+}
+
 fn get_note_button_text(instr: &instr::Instrument, note_num: usize, meas_num: usize) -> egui::RichText {
     let note = instr.get_note(note_num, meas_num);
     let button_text =
@@ -180,17 +1098,13 @@ fn get_note_button_text(instr: &instr::Instrument, note_num: usize, meas_num: us
             if instr.get_note(note_num, start_ind).duration + start_ind - 1 != meas_num {"▶"}
             else {"■"}
         }
-        else if instr.last_clicked.is_some() 
-            && note_num == instr.last_clicked.unwrap().0 
-            && meas_num == instr.last_clicked.unwrap().1 
-            {"☐"}
+        else if instr.last_clicked == Some((note_num, meas_num)) {"☐"}
         else {"■"};
     RichText::new(button_text)
     .color(
-        if note.duration > 0 || note.starts_at.is_some() {Color32::GREEN}
-        else if instr.last_clicked.is_some() 
-            && note_num == instr.last_clicked.unwrap().0 
-            && meas_num == instr.last_clicked.unwrap().1 
+        if note.duration > 0 
+            || note.starts_at.is_some() 
+            || instr.last_clicked == Some((note_num, meas_num)) 
             {Color32::GREEN}
         else {Color32::TRANSPARENT}
     )
@@ -229,18 +1143,68 @@ fn handle_note_button_click(instr: &mut instr::Instrument, note_num: usize, meas
 
 fn send_instrument_state(
     tx: &mut mpsc::Sender<Messages>, 
-    instr: &mut instr::Instrument, 
+    setup: Vec<Messages>,
 ) {
+    for msg in setup {
+        tx.send(msg).unwrap();
+    }
+}
 
-    let mut changed_notes = Vec::new();
-    for i in 0..NOTE_COUNT {
-        for j in 0..MEAS_COUNT {
-            let note = instr.get_note(i, j);
-            if note != Note::default() {
-                changed_notes.push((note, i, j));
-            }
-        }
+/// Everything the engine needs to play the project, ending with `Messages::Play`.
+fn get_setup_messages(
+    tracks: &[Track],
+    tempo: u32,
+    reverb: ReverbParams,
+    master: &[Insert],
+    arrangement: Arrangement,
+) -> Vec<Messages> {
+    let mut setup = vec![Messages::Tempo(tempo), Messages::Reverb(reverb), Messages::Master(master.to_vec())];
+    for (i, track) in tracks.iter().enumerate() {
+        setup.push(Messages::Patch(i, Box::new(track.patch)));
+        setup.push(Messages::Sampler(i, track.sampler.clone()));
+        setup.push(Messages::Kit(i, track.kit.clone()));
+        setup.push(Messages::Wavetable(i, track.wavetable.clone()));
+        setup.push(Messages::Effects(i, track.effects.clone()));
+        setup.push(Messages::Mute(i, track.mute));
+        setup.push(Messages::Solo(i, track.solo));
+    }
+    setup.push(Messages::Arrangement(arrangement));
+    setup.push(Messages::Play(tracks.iter().map(|track| {
+        track.patterns.iter().map(instr::Instrument::get_changed_notes).collect()
+    }).collect()));
+    setup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a project as saved before tracks and patches existed, one note on row 60 at step 3 played by a sawtooth
+    fn old_save() -> String {
+        let row = |i: usize| {
+            let notes: Vec<&str> = (0..instr::MEAS_COUNT)
+                .map(|j| if i == 60 && j == 3 {"(duration:2,starts_at:Some(3))"} else {"(duration:0,starts_at:None)"})
+                .collect();
+            format!("[{}]", notes.join(","))
+        };
+        let rows: Vec<String> = (0..instr::NOTE_COUNT).map(row).collect();
+        format!("(instr:(notes:[{}],last_clicked:Some((60,3))),osc:Sawtooth)", rows.join(","))
     }
 
-    tx.send(Messages::Play(changed_notes)).unwrap();
-}
\ No newline at end of file
+    #[test]
+    fn old_saves_load_onto_the_first_track() {
+        let mut app: StepSequencer = ron::from_str(&old_save()).unwrap();
+        app.migrate();
+        assert_eq!(app.tracks.len(), 1);
+        assert_eq!(app.tracks[0].patch.osc, Oscillator::Sawtooth);
+        assert_eq!(app.tracks[0].patterns[0].get_note(60, 3).duration, 2);
+        assert!(app.instr.is_none() && app.osc.is_none());
+
+        //saved again in the current format, the legacy fields are left out
+        let saved = ron::to_string(&app).unwrap();
+        let mut app: StepSequencer = ron::from_str(&saved).unwrap();
+        app.migrate();
+        assert_eq!(app.tracks[0].patch.osc, Oscillator::Sawtooth);
+        assert_eq!(app.tracks[0].patterns[0].get_note(60, 3).duration, 2);
+    }
+}
@@ -0,0 +1,1174 @@
+use std::f32::consts::PI;
+use std::ops::RangeInclusive;
+
+use crate::filter::{FilterMode, Svf};
+use crate::eq::{EqParams, Equalizer};
+use crate::lfo::{Lfo, Transport};
+use crate::synth::{CHANNELS, SR};
+
+//longest delay time, in seconds
+const MAX_DELAY: f32 = 4.0;
+//below this level an effect's tail or a drum hit counts as finished
+pub const SILENCE: f32 = 1.0e-4;
+
+/// Delay times as note lengths, a sequencer step is an eighth note.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum Division {
+    Sixteenth,
+    EighthTriplet,
+    Eighth,
+    DottedEighth,
+    Quarter,
+    DottedQuarter,
+    Half,
+}
+
+impl Division {
+    pub const ALL: [Division; 7] = [
+        Division::Sixteenth,
+        Division::EighthTriplet,
+        Division::Eighth,
+        Division::DottedEighth,
+        Division::Quarter,
+        Division::DottedQuarter,
+        Division::Half,
+    ];
+
+    pub fn steps(&self) -> f32 {
+        match self {
+            Division::Sixteenth => 0.5,
+            Division::EighthTriplet => 2.0 / 3.0,
+            Division::Eighth => 1.0,
+            Division::DottedEighth => 1.5,
+            Division::Quarter => 2.0,
+            Division::DottedQuarter => 3.0,
+            Division::Half => 4.0,
+        }
+    }
+}
+
+impl std::fmt::Display for Division {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Division::Sixteenth => write!(f, "1/16"),
+            Division::EighthTriplet => write!(f, "1/8 T"),
+            Division::Eighth => write!(f, "1/8"),
+            Division::DottedEighth => write!(f, "1/8 D"),
+            Division::Quarter => write!(f, "1/4"),
+            Division::DottedQuarter => write!(f, "1/4 D"),
+            Division::Half => write!(f, "1/2"),
+        }
+    }
+}
+
+/// Echoes added on top of the dry track, the repeats are filtered on every pass.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct DelayParams {
+    pub sync: bool,
+    pub division: Division,
+    pub time: f32, //in seconds when not synced
+    pub feedback: f32,
+    pub mix: f32, //level of the repeats, the dry signal is left as is
+    pub low_cut: f32, //in Hz
+    pub high_cut: f32, //in Hz
+    pub ping_pong: bool, //bounce repeats between left and right
+}
+
+impl Default for DelayParams {
+    fn default() -> Self {
+        DelayParams {
+            sync: true,
+            division: Division::DottedEighth,
+            time: 0.3,
+            feedback: 0.4,
+            mix: 0.3,
+            low_cut: 200.0,
+            high_cut: 5000.0,
+            ping_pong: false,
+        }
+    }
+}
+
+/// Effect settings of a track, the inserts are applied in order to its voices after they are mixed.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Effects {
+    pub inserts: Vec<Insert>,
+    pub reverb_send: f32, //level sent to the shared reverb bus, after the inserts
+}
+
+impl Default for Effects {
+    fn default() -> Self {
+        Effects { inserts: vec![], reverb_send: 0.0 }
+    }
+}
+
+/// One slot of an insert chain.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Insert {
+    pub id: u64, //unique within its chain, follows the insert when it's moved
+    pub bypass: bool,
+    pub effect: EffectParams,
+}
+
+impl Default for Insert {
+    fn default() -> Self {
+        Insert { id: 0, bypass: false, effect: EffectParams::Eq(EqParams::default()) }
+    }
+}
+
+impl Insert {
+    /// A new insert with an id none of `inserts` has.
+    pub fn new(inserts: &[Insert], effect: EffectParams) -> Insert {
+        let id = inserts.iter().map(|insert| insert.id + 1).max().unwrap_or(0);
+        Insert { id, bypass: false, effect }
+    }
+
+    /// Gives inserts sharing an id, as saved before they had one, ids of their own.
+    pub fn renumber(inserts: &mut [Insert]) {
+        for i in 1..inserts.len() {
+            if inserts[..i].iter().any(|insert| insert.id == inserts[i].id) {
+                inserts[i].id = Insert::new(inserts, inserts[i].effect).id;
+            }
+        }
+    }
+}
+
+/// Settings of any insert effect, the variant decides which processor runs them.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum EffectParams {
+    Distortion(DistortionParams),
+    Bitcrusher(BitcrusherParams),
+    Decimator(DecimatorParams),
+    Chorus(ModulationParams),
+    Flanger(ModulationParams),
+    Phaser(ModulationParams),
+    Eq(EqParams),
+    Compressor(CompressorParams),
+    Delay(DelayParams),
+}
+
+impl EffectParams {
+    /// One of each effect with its default settings, in the order they are offered.
+    pub fn defaults() -> [EffectParams; 9] {
+        [
+            EffectParams::Distortion(DistortionParams::default()),
+            EffectParams::Bitcrusher(BitcrusherParams::default()),
+            EffectParams::Decimator(DecimatorParams::default()),
+            EffectParams::Chorus(ModulationParams { rate: 0.8, depth: 0.5, feedback: 0.0, mix: 0.5, ..ModulationParams::default() }),
+            EffectParams::Flanger(ModulationParams { rate: 0.2, depth: 0.7, feedback: 0.6, mix: 0.5, ..ModulationParams::default() }),
+            EffectParams::Phaser(ModulationParams { rate: 0.4, depth: 0.8, feedback: 0.5, mix: 0.5, ..ModulationParams::default() }),
+            EffectParams::Eq(EqParams::default()),
+            EffectParams::Compressor(CompressorParams::default()),
+            EffectParams::Delay(DelayParams::default()),
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectParams::Distortion(_) => "Distortion",
+            EffectParams::Bitcrusher(_) => "Bitcrusher",
+            EffectParams::Decimator(_) => "Decimator",
+            EffectParams::Chorus(_) => "Chorus",
+            EffectParams::Flanger(_) => "Flanger",
+            EffectParams::Phaser(_) => "Phaser",
+            EffectParams::Eq(_) => "EQ",
+            EffectParams::Compressor(_) => "Compressor",
+            EffectParams::Delay(_) => "Delay",
+        }
+    }
+
+    /// Every setting as a control, `placement` lists the tracks a sidechain can listen to.
+    pub fn params(&mut self, placement: &Placement<'_>) -> Vec<Param<'_>> {
+        match self {
+            EffectParams::Distortion(params) => Distortion::params(params, placement),
+            EffectParams::Bitcrusher(params) => Bitcrusher::params(params, placement),
+            EffectParams::Decimator(params) => Decimator::params(params, placement),
+            EffectParams::Chorus(params) | EffectParams::Flanger(params) => ModDelay::params(params, placement),
+            EffectParams::Phaser(params) => Phaser::params(params, placement),
+            EffectParams::Eq(params) => Equalizer::params(params, placement),
+            EffectParams::Compressor(params) => Compressor::params(params, placement),
+            EffectParams::Delay(params) => Delay::params(params, placement),
+        }
+    }
+}
+
+/// A setting of an effect, with what the UI needs to draw a control for it.
+pub struct Param<'a> {
+    pub name: &'static str,
+    pub value: Value<'a>,
+    pub enabled: bool, //false when the other settings leave it without effect
+    pub same_line: bool, //drawn next to the previous setting instead of below it
+}
+
+pub enum Value<'a> {
+    Float { value: &'a mut f32, range: RangeInclusive<f32>, unit: &'static str, logarithmic: bool },
+    Int { value: &'a mut u32, range: RangeInclusive<u32> },
+    Toggle(&'a mut bool),
+    Choice(Choice<'a>),
+}
+
+/// One of a list of options, picked by index.
+pub struct Choice<'a> {
+    pub labels: Vec<String>,
+    pub selected: Option<usize>,
+    select: Box<dyn FnMut(usize) + 'a>,
+}
+
+impl<'a> Choice<'a> {
+    pub fn select(&mut self, index: usize) {
+        if index < self.labels.len() && self.selected != Some(index) {
+            (self.select)(index);
+            self.selected = Some(index);
+        }
+    }
+}
+
+impl<'a> Param<'a> {
+    fn new(name: &'static str, value: Value<'a>) -> Param<'a> {
+        Param { name, value, enabled: true, same_line: false }
+    }
+
+    pub fn float(name: &'static str, value: &'a mut f32, range: RangeInclusive<f32>, unit: &'static str) -> Param<'a> {
+        Param::new(name, Value::Float { value, range, unit, logarithmic: false })
+    }
+
+    pub fn int(name: &'static str, value: &'a mut u32, range: RangeInclusive<u32>) -> Param<'a> {
+        Param::new(name, Value::Int { value, range })
+    }
+
+    pub fn toggle(name: &'static str, value: &'a mut bool) -> Param<'a> {
+        Param::new(name, Value::Toggle(value))
+    }
+
+    /// Picks `value` out of `options`, each shown with its label.
+    pub fn choice<T: Copy + PartialEq + 'a>(name: &'static str, value: &'a mut T, options: Vec<(T, String)>) -> Param<'a> {
+        let selected = options.iter().position(|(option, _)| *option == *value);
+        let (options, labels): (Vec<T>, Vec<String>) = options.into_iter().unzip();
+        let select = Box::new(move |index: usize| *value = options[index]);
+        Param::new(name, Value::Choice(Choice { labels, selected, select }))
+    }
+
+    /// A choice labelled the way the options display themselves.
+    pub fn options<T: Copy + PartialEq + std::fmt::Display + 'a>(name: &'static str, value: &'a mut T, options: &[T]) -> Param<'a> {
+        Param::choice(name, value, options.iter().map(|option| (*option, option.to_string())).collect())
+    }
+
+    pub fn logarithmic(mut self) -> Param<'a> {
+        if let Value::Float { logarithmic, .. } = &mut self.value {
+            *logarithmic = true;
+        }
+        self
+    }
+
+    pub fn enabled(self, enabled: bool) -> Param<'a> {
+        Param { enabled, ..self }
+    }
+
+    pub fn same_line(self) -> Param<'a> {
+        Param { same_line: true, ..self }
+    }
+}
+
+/// Where an insert chain sits, for settings that point at other tracks.
+#[derive(Clone, Copy, Default)]
+pub struct Placement<'a> {
+    pub names: &'a [String], //every track
+    pub track: Option<usize>, //the track the chain is on, `None` on the master
+}
+
+/// What an effect gets besides its input.
+#[derive(Clone, Copy)]
+pub struct Context<'a> {
+    pub transport: Transport,
+    pub keys: &'a [Vec<f32>], //each track's output after its inserts, for sidechains
+}
+
+/// Block-based processing of interleaved stereo audio in place, each effect taking its own settings.
+pub trait Effect {
+    type Params;
+
+    fn set_params(&mut self, params: &Self::Params);
+
+    /// Controls for every setting, in the order they are drawn.
+    fn params<'a>(params: &'a mut Self::Params, placement: &Placement<'_>) -> Vec<Param<'a>>;
+
+    fn process(&mut self, data: &mut [f32], context: &Context<'_>);
+
+    /// Whether it still puts out sound after its input went silent.
+    fn is_ringing(&self) -> bool {
+        false
+    }
+}
+
+/// The processor of any insert effect.
+enum Processor {
+    Distortion(Distortion),
+    Bitcrusher(Bitcrusher),
+    Decimator(Decimator),
+    Chorus(ModDelay),
+    Flanger(ModDelay),
+    Phaser(Phaser),
+    Eq(Equalizer),
+    Compressor(Compressor),
+    Delay(Delay),
+}
+
+impl Processor {
+    fn new(params: &EffectParams) -> Processor {
+        let mut processor = match params {
+            EffectParams::Distortion(_) => Processor::Distortion(Distortion::default()),
+            EffectParams::Bitcrusher(_) => Processor::Bitcrusher(Bitcrusher::default()),
+            EffectParams::Decimator(_) => Processor::Decimator(Decimator::default()),
+            EffectParams::Chorus(_) => Processor::Chorus(ModDelay::new(ModDelayKind::Chorus)),
+            EffectParams::Flanger(_) => Processor::Flanger(ModDelay::new(ModDelayKind::Flanger)),
+            EffectParams::Phaser(_) => Processor::Phaser(Phaser::default()),
+            EffectParams::Eq(_) => Processor::Eq(Equalizer::default()),
+            EffectParams::Compressor(_) => Processor::Compressor(Compressor::default()),
+            EffectParams::Delay(_) => Processor::Delay(Delay::default()),
+        };
+        processor.set_params(params);
+        processor
+    }
+
+    /// Takes new settings, false when they are meant for another kind of effect.
+    fn set_params(&mut self, params: &EffectParams) -> bool {
+        match (self, params) {
+            (Processor::Distortion(effect), EffectParams::Distortion(params)) => effect.set_params(params),
+            (Processor::Bitcrusher(effect), EffectParams::Bitcrusher(params)) => effect.set_params(params),
+            (Processor::Decimator(effect), EffectParams::Decimator(params)) => effect.set_params(params),
+            (Processor::Chorus(effect), EffectParams::Chorus(params)) => effect.set_params(params),
+            (Processor::Flanger(effect), EffectParams::Flanger(params)) => effect.set_params(params),
+            (Processor::Phaser(effect), EffectParams::Phaser(params)) => effect.set_params(params),
+            (Processor::Eq(effect), EffectParams::Eq(params)) => effect.set_params(params),
+            (Processor::Compressor(effect), EffectParams::Compressor(params)) => effect.set_params(params),
+            (Processor::Delay(effect), EffectParams::Delay(params)) => effect.set_params(params),
+            _ => return false,
+        }
+        true
+    }
+
+    fn process(&mut self, data: &mut [f32], context: &Context<'_>) {
+        match self {
+            Processor::Distortion(effect) => effect.process(data, context),
+            Processor::Bitcrusher(effect) => effect.process(data, context),
+            Processor::Decimator(effect) => effect.process(data, context),
+            Processor::Chorus(effect) | Processor::Flanger(effect) => effect.process(data, context),
+            Processor::Phaser(effect) => effect.process(data, context),
+            Processor::Eq(effect) => effect.process(data, context),
+            Processor::Compressor(effect) => effect.process(data, context),
+            Processor::Delay(effect) => effect.process(data, context),
+        }
+    }
+
+    fn is_ringing(&self) -> bool {
+        match self {
+            Processor::Distortion(effect) => effect.is_ringing(),
+            Processor::Bitcrusher(effect) => effect.is_ringing(),
+            Processor::Decimator(effect) => effect.is_ringing(),
+            Processor::Chorus(effect) | Processor::Flanger(effect) => effect.is_ringing(),
+            Processor::Phaser(effect) => effect.is_ringing(),
+            Processor::Eq(effect) => effect.is_ringing(),
+            Processor::Compressor(effect) => effect.is_ringing(),
+            Processor::Delay(effect) => effect.is_ringing(),
+        }
+    }
+}
+
+/// Processors of an insert chain, kept in step with its settings.
+#[derive(Default)]
+pub struct Chain {
+    inserts: Vec<Insert>,
+    effects: Vec<Processor>,
+}
+
+impl Chain {
+    /// Applies new settings, effects that stay in the chain keep their state even when moved.
+    pub fn set(&mut self, inserts: &[Insert]) {
+        let mut old: Vec<(u64, Processor)> = self.inserts.iter()
+            .map(|insert| insert.id)
+            .zip(self.effects.drain(..))
+            .collect();
+        self.effects = inserts.iter().map(|insert| {
+            //an id now given to another kind of effect gets a new processor
+            let i = old.iter_mut().position(|(id, effect)| *id == insert.id && effect.set_params(&insert.effect));
+            match i {
+                Some(i) => old.remove(i).1,
+                None => Processor::new(&insert.effect),
+            }
+        }).collect();
+        self.inserts = inserts.to_vec();
+    }
+
+    pub fn process(&mut self, data: &mut [f32], context: &Context<'_>) {
+        for (insert, effect) in self.inserts.iter().zip(self.effects.iter_mut()) {
+            if !insert.bypass {
+                effect.process(data, context);
+            }
+        }
+    }
+
+    pub fn is_ringing(&self) -> bool {
+        self.inserts.iter().zip(self.effects.iter()).any(|(insert, effect)| !insert.bypass && effect.is_ringing())
+    }
+
+    /// Tracks the active compressors listen to.
+    pub fn sidechains(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inserts.iter().filter_map(|insert| match insert.effect {
+            EffectParams::Compressor(CompressorParams { sidechain, .. }) if !insert.bypass => sidechain,
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum Shape {
+    Soft, //tanh saturation
+    Hard, //clipped at full scale
+    Fold, //folded back from full scale
+}
+
+impl Shape {
+    pub const ALL: [Shape; 3] = [Shape::Soft, Shape::Hard, Shape::Fold];
+}
+
+impl std::fmt::Display for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Waveshaper driven by a gain stage.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct DistortionParams {
+    pub shape: Shape,
+    pub drive: f32, //in dB
+    pub output: f32, //in dB
+    pub mix: f32,
+}
+
+impl Default for DistortionParams {
+    fn default() -> Self {
+        DistortionParams { shape: Shape::Soft, drive: 12.0, output: -6.0, mix: 1.0 }
+    }
+}
+
+/// Quantises samples to fewer bits.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct BitcrusherParams {
+    pub bits: u32,
+    pub mix: f32,
+}
+
+impl Default for BitcrusherParams {
+    fn default() -> Self {
+        BitcrusherParams { bits: 8, mix: 1.0 }
+    }
+}
+
+/// Holds samples to lower the sample rate, without filtering the aliases away.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct DecimatorParams {
+    pub rate: f32, //in Hz
+    pub mix: f32,
+}
+
+impl Default for DecimatorParams {
+    fn default() -> Self {
+        DecimatorParams { rate: 8000.0, mix: 1.0 }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Distortion {
+    params: DistortionParams,
+}
+
+impl Effect for Distortion {
+    type Params = DistortionParams;
+
+    fn set_params(&mut self, params: &DistortionParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut DistortionParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        vec![
+            Param::options("Shape", &mut params.shape, &Shape::ALL),
+            Param::float("Drive", &mut params.drive, 0.0..=36.0, " dB"),
+            Param::float("Output", &mut params.output, -24.0..=6.0, " dB"),
+            Param::float("Mix", &mut params.mix, 0.0..=1.0, ""),
+        ]
+    }
+
+    fn process(&mut self, data: &mut [f32], _context: &Context<'_>) {
+        let params = self.params;
+        let drive = db_to_gain(params.drive);
+        let output = db_to_gain(params.output);
+        for sample in data.iter_mut() {
+            let x = drive * *sample;
+            let shaped = match params.shape {
+                Shape::Soft => x.tanh(),
+                Shape::Hard => x.clamp(-1.0, 1.0),
+                Shape::Fold => 1.0 - ((x + 1.0).rem_euclid(4.0) - 2.0).abs(),
+            };
+            *sample += params.mix * (output * shaped - *sample);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Bitcrusher {
+    params: BitcrusherParams,
+}
+
+impl Effect for Bitcrusher {
+    type Params = BitcrusherParams;
+
+    fn set_params(&mut self, params: &BitcrusherParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut BitcrusherParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        vec![
+            Param::int("Bits", &mut params.bits, 1..=16),
+            Param::float("Mix", &mut params.mix, 0.0..=1.0, ""),
+        ]
+    }
+
+    fn process(&mut self, data: &mut [f32], _context: &Context<'_>) {
+        let params = self.params;
+        let step = 2.0 / (params.bits.clamp(1, 16) as f32).exp2();
+        for sample in data.iter_mut() {
+            let crushed = (*sample / step).round() * step;
+            *sample += params.mix * (crushed - *sample);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Decimator {
+    params: DecimatorParams,
+    phase: f32,
+    held: [f32; 2],
+}
+
+impl Effect for Decimator {
+    type Params = DecimatorParams;
+
+    fn set_params(&mut self, params: &DecimatorParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut DecimatorParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        vec![
+            Param::float("Rate", &mut params.rate, 200.0..=SR as f32, " Hz").logarithmic(),
+            Param::float("Mix", &mut params.mix, 0.0..=1.0, ""),
+        ]
+    }
+
+    fn process(&mut self, data: &mut [f32], _context: &Context<'_>) {
+        let params = self.params;
+        let step = params.rate.clamp(20.0, SR as f32) / SR as f32;
+        for frame in data.chunks_mut(CHANNELS as usize) {
+            self.phase += step;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                self.held.copy_from_slice(frame);
+            }
+            for (sample, held) in frame.iter_mut().zip(self.held) {
+                *sample += params.mix * (held - *sample);
+            }
+        }
+    }
+}
+
+/// Settings shared by the chorus, flanger and phaser. Synced rates follow the sequencer
+/// like a synced LFO does.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct ModulationParams {
+    pub rate: f32, //in Hz when free-running
+    pub sync: bool,
+    pub steps: u32, //cycle length in sequencer steps when synced
+    pub depth: f32,
+    pub feedback: f32,
+    pub mix: f32,
+}
+
+impl Default for ModulationParams {
+    fn default() -> Self {
+        ModulationParams { rate: 0.5, sync: false, steps: 16, depth: 0.5, feedback: 0.0, mix: 0.5 }
+    }
+}
+
+impl ModulationParams {
+    /// Controls shared by the chorus, flanger and phaser.
+    fn params(&mut self) -> Vec<Param<'_>> {
+        let sync = self.sync;
+        let mut list = vec![Param::toggle("Tempo sync", &mut self.sync)];
+        if sync {
+            list.push(Param::int("Steps per cycle", &mut self.steps, 1..=128));
+        }
+        else {
+            list.push(Param::float("Rate", &mut self.rate, 0.01..=10.0, " Hz").logarithmic());
+        }
+        list.push(Param::float("Depth", &mut self.depth, 0.0..=1.0, ""));
+        list.push(Param::float("Feedback", &mut self.feedback, -0.95..=0.95, ""));
+        list.push(Param::float("Mix", &mut self.mix, 0.0..=1.0, ""));
+        list
+    }
+
+    /// Sweep position between 0.0 and 1.0, `offset` shifts the phase by part of a cycle.
+    fn sweep(&self, transport: &Transport, offset: f64) -> f32 {
+        let lfo = Lfo { rate: self.rate, sync: self.sync, steps: self.steps, ..Lfo::default() };
+        let phase = (lfo.cycles(transport) + offset).fract() as f32;
+        0.5 - 0.5 * (2.0 * PI * phase).cos()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModDelayKind {
+    Chorus,
+    Flanger,
+}
+
+/// Chorus and flanger: the signal mixed with a copy whose delay time is swept.
+#[derive(Clone, Debug)]
+pub struct ModDelay {
+    params: ModulationParams,
+    kind: ModDelayKind,
+    lines: [Vec<f32>; 2],
+    write: usize,
+    quiet: usize, //frames since anything audible went into the lines
+}
+
+impl ModDelay {
+    pub fn new(kind: ModDelayKind) -> ModDelay {
+        //longest swept delay is 30 ms
+        let len = SR as usize * 30 / 1000 + 2;
+        ModDelay { params: ModulationParams::default(), kind, lines: [vec![0.0; len], vec![0.0; len]], write: 0, quiet: len }
+    }
+}
+
+impl Effect for ModDelay {
+    type Params = ModulationParams;
+
+    fn set_params(&mut self, params: &ModulationParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut ModulationParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        params.params()
+    }
+
+    fn process(&mut self, data: &mut [f32], context: &Context<'_>) {
+        let params = self.params;
+        let transport = context.transport;
+        //base delay and sweep range in seconds, the chorus sweeps its channels out of phase
+        let (base, range, spread) = match self.kind {
+            ModDelayKind::Chorus => (0.012, 0.015, 0.25),
+            ModDelayKind::Flanger => (0.0005, 0.006, 0.0),
+        };
+        let feedback = params.feedback.clamp(-0.95, 0.95);
+        let len = self.lines[0].len();
+        for (i, frame) in data.chunks_mut(CHANNELS as usize).enumerate() {
+            let transport = Transport { frame: transport.frame + i as u64, ..transport };
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let sweep = params.sweep(&transport, spread * channel as f64);
+                let delay = (base + range * params.depth.clamp(0.0, 1.0) * sweep) * SR as f32;
+                let pos = (self.write as f32 - delay).rem_euclid(len as f32);
+                let index = pos as usize % len;
+                let next = (index + 1) % len;
+                let line = &mut self.lines[channel];
+                let wet = line[index] + (line[next] - line[index]) * pos.fract();
+                line[self.write] = *sample + feedback * wet;
+                *sample += params.mix * (wet - *sample);
+            }
+            let audible = self.lines.iter().any(|line| line[self.write].abs() > SILENCE);
+            self.quiet = if audible {0} else {self.quiet.saturating_add(1)};
+            self.write = (self.write + 1) % len;
+        }
+    }
+
+    /// Whether the lines still hold anything audible, with feedback it keeps going round.
+    fn is_ringing(&self) -> bool {
+        self.quiet < self.lines[0].len()
+    }
+}
+
+const PHASER_STAGES: usize = 6;
+//range the phaser's notches sweep over, in Hz
+const PHASER_LOW: f32 = 200.0;
+const PHASER_HIGH: f32 = 4000.0;
+
+/// Cascade of swept first-order all-passes, mixed with the dry signal to form moving notches.
+#[derive(Clone, Debug, Default)]
+pub struct Phaser {
+    params: ModulationParams,
+    states: [[f32; PHASER_STAGES]; 2],
+    last: [f32; 2],
+}
+
+impl Effect for Phaser {
+    type Params = ModulationParams;
+
+    fn set_params(&mut self, params: &ModulationParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut ModulationParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        params.params()
+    }
+
+    fn process(&mut self, data: &mut [f32], context: &Context<'_>) {
+        let params = self.params;
+        let transport = context.transport;
+        let feedback = params.feedback.clamp(-0.95, 0.95);
+        for (i, frame) in data.chunks_mut(CHANNELS as usize).enumerate() {
+            let transport = Transport { frame: transport.frame + i as u64, ..transport };
+            let sweep = params.sweep(&transport, 0.0) * params.depth.clamp(0.0, 1.0);
+            let freq = PHASER_LOW * (PHASER_HIGH / PHASER_LOW).powf(sweep);
+            let t = (PI * freq / SR as f32).tan();
+            let a = (t - 1.0) / (t + 1.0);
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample + feedback * self.last[channel];
+                for state in self.states[channel].iter_mut() {
+                    let y = a * x + *state;
+                    *state = x - a * y;
+                    x = y;
+                }
+                self.last[channel] = x;
+                *sample += params.mix * (x - *sample);
+            }
+        }
+    }
+
+    /// Whether the all-passes or the feedback still hold anything audible.
+    fn is_ringing(&self) -> bool {
+        self.states.iter().flatten().chain(self.last.iter()).any(|state| state.abs() > SILENCE)
+    }
+}
+
+/// Compressor settings, `sidechain` keys the detector from another track's output.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct CompressorParams {
+    pub threshold: f32, //in dB
+    pub ratio: f32,
+    pub attack: f32, //in seconds
+    pub release: f32, //in seconds
+    pub makeup: f32, //in dB
+    pub sidechain: Option<usize>, //track index, `None` listens to the track itself
+}
+
+impl Default for CompressorParams {
+    fn default() -> Self {
+        CompressorParams {
+            threshold: -20.0,
+            ratio: 4.0,
+            attack: 0.01,
+            release: 0.15,
+            makeup: 0.0,
+            sidechain: None,
+        }
+    }
+}
+
+/// Feed-forward compressor with a stereo-linked peak detector.
+#[derive(Clone, Debug, Default)]
+pub struct Compressor {
+    params: CompressorParams,
+    envelope: f32, //detector level in dB above the threshold
+}
+
+impl Effect for Compressor {
+    type Params = CompressorParams;
+
+    fn set_params(&mut self, params: &CompressorParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut CompressorParams, placement: &Placement<'_>) -> Vec<Param<'a>> {
+        let mut list = vec![
+            Param::float("Threshold", &mut params.threshold, -60.0..=0.0, " dB"),
+            Param::float("Ratio", &mut params.ratio, 1.0..=20.0, "").logarithmic(),
+            Param::float("Attack", &mut params.attack, 0.0001..=0.2, " s").logarithmic(),
+            Param::float("Release", &mut params.release, 0.01..=2.0, " s").logarithmic(),
+            Param::float("Makeup", &mut params.makeup, 0.0..=24.0, " dB"),
+        ];
+        //the master has no track of its own to key from
+        if let Some(track) = placement.track {
+            let others = placement.names.iter().enumerate()
+                .filter(|(i, _)| *i != track)
+                .map(|(i, name)| (Some(i), name.clone()));
+            let options = std::iter::once((None, "Self".to_string())).chain(others).collect();
+            list.push(Param::choice("Key", &mut params.sidechain, options));
+        }
+        list
+    }
+
+    /// Listens to the sidechain track's output, or to its own input without one.
+    fn process(&mut self, data: &mut [f32], context: &Context<'_>) {
+        let params = self.params;
+        let key = params.sidechain.and_then(|source| context.keys.get(source));
+        let coefficient = |time: f32| (-1.0 / (time.max(1.0e-4) * SR as f32)).exp();
+        let attack = coefficient(params.attack);
+        let release = coefficient(params.release);
+        let slope = 1.0 - 1.0 / params.ratio.max(1.0);
+        let makeup = db_to_gain(params.makeup);
+        let channels = CHANNELS as usize;
+        for (i, frame) in data.chunks_mut(channels).enumerate() {
+            let detected = key.and_then(|key| key.get(i * channels..(i + 1) * channels)).unwrap_or(frame);
+            let peak = detected.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            let over = (20.0 * peak.max(1.0e-6).log10() - params.threshold).max(0.0);
+            let coefficient = if over > self.envelope {attack} else {release};
+            self.envelope = over + coefficient * (self.envelope - over);
+            let gain = db_to_gain(-slope * self.envelope) * makeup;
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// Stereo delay line with a filter in each channel's feedback path.
+#[derive(Clone, Debug)]
+pub struct Delay {
+    params: DelayParams,
+    lines: [Vec<f32>; 2],
+    write: usize,
+    low_cut: [Svf; 2],
+    high_cut: [Svf; 2],
+    peak: f32, //loudest repeat in the last processed buffer
+    frames: usize, //current delay time
+    quiet: usize, //frames since anything audible went into the lines
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        let len = (MAX_DELAY * SR as f32) as usize + 1;
+        Delay {
+            params: DelayParams::default(),
+            lines: [vec![0.0; len], vec![0.0; len]],
+            write: 0,
+            low_cut: Default::default(),
+            high_cut: Default::default(),
+            peak: 0.0,
+            frames: 0,
+            quiet: len,
+        }
+    }
+}
+
+impl Effect for Delay {
+    type Params = DelayParams;
+
+    fn set_params(&mut self, params: &DelayParams) {
+        self.params = *params;
+    }
+
+    fn params<'a>(params: &'a mut DelayParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        let sync = params.sync;
+        let mut list = vec![
+            Param::toggle("Tempo sync", &mut params.sync),
+            Param::toggle("Ping-pong", &mut params.ping_pong).same_line(),
+        ];
+        if sync {
+            list.push(Param::options("Division", &mut params.division, &Division::ALL));
+        }
+        else {
+            list.push(Param::float("Time", &mut params.time, 0.01..=MAX_DELAY, " s").logarithmic());
+        }
+        list.push(Param::float("Feedback", &mut params.feedback, 0.0..=0.95, ""));
+        list.push(Param::float("Mix", &mut params.mix, 0.0..=1.0, ""));
+        list.push(Param::float("Low cut", &mut params.low_cut, 20.0..=2000.0, " Hz").logarithmic());
+        list.push(Param::float("High cut", &mut params.high_cut, 500.0..=20000.0, " Hz").logarithmic());
+        list
+    }
+
+    /// Adds the repeats to the input, synced times follow the length of a step.
+    fn process(&mut self, data: &mut [f32], context: &Context<'_>) {
+        self.peak = 0.0;
+        let params = self.params;
+        let frames_per_step = context.transport.frames_per_step;
+        let len = self.lines[0].len();
+        let frames = if params.sync {params.division.steps() * frames_per_step} else {params.time * SR as f32};
+        let frames = (frames.round() as usize).clamp(1, len - 1);
+        self.frames = frames;
+        let feedback = params.feedback.clamp(0.0, 0.95);
+        for frame in data.chunks_mut(CHANNELS as usize) {
+            let read = (self.write + len - frames) % len;
+            let mut repeats = [0.0; 2];
+            for (channel, repeat) in repeats.iter_mut().enumerate() {
+                let echo = self.lines[channel][read];
+                let echo = self.low_cut[channel].process(echo, FilterMode::HighPass, params.low_cut, 0.0);
+                *repeat = self.high_cut[channel].process(echo, FilterMode::LowPass, params.high_cut, 0.0);
+            }
+            let (left, right) = (frame[0], frame[1]);
+            let inputs =
+                if params.ping_pong {[(left + right) / 2.0 + feedback * repeats[1], feedback * repeats[0]]}
+                else {[left + feedback * repeats[0], right + feedback * repeats[1]]};
+            self.quiet = if inputs.iter().any(|input| input.abs() > SILENCE) {0} else {self.quiet.saturating_add(1)};
+            for (channel, input) in inputs.into_iter().enumerate() {
+                self.lines[channel][self.write] = input;
+                frame[channel] += params.mix * repeats[channel];
+                self.peak = self.peak.max(repeats[channel].abs());
+            }
+            self.write = (self.write + 1) % len;
+        }
+    }
+
+    /// Whether repeats are still audible or still waiting in the lines.
+    fn is_ringing(&self) -> bool {
+        self.peak > SILENCE || self.quiet < self.frames
+    }
+}
+
+//Freeverb tunings, in frames at 44.1 kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_WET_GAIN: f32 = 3.0;
+
+/// Settings of the reverb bus shared by every track.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct ReverbParams {
+    pub enabled: bool,
+    pub size: f32,
+    pub damping: f32, //how quickly high frequencies die away
+    pub mix: f32, //level of the bus return
+}
+
+impl Default for ReverbParams {
+    fn default() -> Self {
+        ReverbParams { enabled: true, size: 0.6, damping: 0.4, mix: 0.5 }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Comb {
+        Comb { buffer: vec![0.0; len], index: 0, store: 0.0 }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.store = output * (1.0 - damp) + self.store * damp;
+        self.buffer[self.index] = input + self.store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Allpass {
+        Allpass { buffer: vec![0.0; len], index: 0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// Freeverb: parallel damped combs into series allpasses, the right channel's delays are
+/// slightly longer to decorrelate it from the left.
+#[derive(Clone, Debug)]
+pub struct Reverb {
+    pub params: ReverbParams,
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    quiet: usize, //frames since the input or the return was audible
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        let scale = |len: usize, channel: usize| (len + channel * STEREO_SPREAD) * SR as usize / 44100;
+        Reverb {
+            params: ReverbParams::default(),
+            combs: [0, 1].map(|channel| COMB_TUNINGS.iter().map(|len| Comb::new(scale(*len, channel))).collect()),
+            allpasses: [0, 1].map(|channel| ALLPASS_TUNINGS.iter().map(|len| Allpass::new(scale(*len, channel))).collect()),
+            quiet: usize::MAX,
+        }
+    }
+}
+
+impl Reverb {
+    /// Whether the return is audible, or a hit is still on its way through the longest path.
+    pub fn is_ringing(&self) -> bool {
+        let longest = |channel: usize| {
+            let comb = self.combs[channel].iter().map(|comb| comb.buffer.len()).max().unwrap_or(0);
+            comb + self.allpasses[channel].iter().map(|allpass| allpass.buffer.len()).sum::<usize>()
+        };
+        self.params.enabled && self.quiet <= longest(0).max(longest(1))
+    }
+
+    /// Replaces the interleaved bus input with the reverb's fully wet return.
+    pub fn process(&mut self, data: &mut [f32]) {
+        if !self.params.enabled {
+            self.quiet = usize::MAX;
+            data.iter_mut().for_each(|d| *d = 0.0);
+            return;
+        }
+        let feedback = 0.7 + 0.28 * self.params.size.clamp(0.0, 1.0);
+        let damp = 0.4 * self.params.damping.clamp(0.0, 1.0);
+        for frame in data.chunks_mut(CHANNELS as usize) {
+            let mut audible = frame.iter().any(|sample| sample.abs() > SILENCE);
+            let input = (frame[0] + frame[1]) * REVERB_INPUT_GAIN;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut output: f32 = self.combs[channel].iter_mut()
+                    .map(|comb| comb.process(input, feedback, damp))
+                    .sum();
+                for allpass in self.allpasses[channel].iter_mut() {
+                    output = allpass.process(output);
+                }
+                *sample = REVERB_WET_GAIN * self.params.mix * output;
+                audible |= output.abs() > SILENCE;
+            }
+            self.quiet = if audible {0} else {self.quiet.saturating_add(1)};
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(effect: &Processor) -> f32 {
+        match effect {
+            Processor::Compressor(compressor) => compressor.envelope,
+            _ => panic!("not a compressor"),
+        }
+    }
+
+    #[test]
+    fn moved_inserts_keep_their_state() {
+        let compressor = EffectParams::Compressor(CompressorParams { attack: 0.0001, ..CompressorParams::default() });
+        let first = Insert::new(&[], compressor);
+        let second = Insert::new(&[first], compressor);
+        //only the second compressor hears the loud part
+        let mut chain = Chain::default();
+        chain.set(&[Insert { bypass: true, ..first }, second]);
+        chain.process(&mut vec![1.0; 256], &Context { transport: Transport::default(), keys: &[] });
+        chain.set(&[first, second]);
+        let loud = envelope(&chain.effects[1]);
+        assert!(loud > 0.0);
+        assert_eq!(envelope(&chain.effects[0]), 0.0);
+
+        chain.set(&[second, first]);
+        assert_eq!(envelope(&chain.effects[0]), loud);
+        assert_eq!(envelope(&chain.effects[1]), 0.0);
+    }
+
+    #[test]
+    fn renumbered_inserts_have_distinct_ids() {
+        let mut inserts = [Insert::default(); 3];
+        Insert::renumber(&mut inserts);
+        assert_eq!(inserts.map(|insert| insert.id), [0, 1, 2]);
+    }
+
+    #[test]
+    fn sidechain_choice_skips_the_own_track() {
+        let names = ["Kick".to_string(), "Bass".to_string(), "Pad".to_string()];
+        let mut effect = EffectParams::Compressor(CompressorParams::default());
+        {
+            let mut params = effect.params(&Placement { names: &names, track: Some(1) });
+            let Some(Value::Choice(mut choice)) = params.pop().map(|param| param.value) else {panic!("no sidechain choice")};
+            assert_eq!(choice.labels, ["Self", "Kick", "Pad"]);
+            choice.select(2);
+        }
+        assert_eq!(effect, EffectParams::Compressor(CompressorParams { sidechain: Some(2), ..CompressorParams::default() }));
+    }
+
+    #[test]
+    fn feedback_effects_ring_after_their_input_stops() {
+        for params in EffectParams::defaults() {
+            if !matches!(params, EffectParams::Flanger(_) | EffectParams::Phaser(_)) {continue;}
+            let mut effect = Processor::new(&params);
+            let context = Context { transport: Transport::default(), keys: &[] };
+            let mut data = vec![0.0; 64 * CHANNELS as usize];
+            data[0] = 1.0;
+            effect.process(&mut data, &context);
+            assert!(effect.is_ringing(), "{} stopped with its feedback", params.name());
+            let mut buffers = 0;
+            while effect.is_ringing() && buffers < 10000 {
+                effect.process(&mut vec![0.0; 64 * CHANNELS as usize], &context);
+                buffers += 1;
+            }
+            assert!(!effect.is_ringing(), "{} never stops", params.name());
+        }
+    }
+
+    #[test]
+    fn reverb_rings_from_a_short_hit_until_its_tail_dies() {
+        let mut reverb = Reverb::default();
+        let mut data = vec![0.0; 64 * CHANNELS as usize];
+        data[0] = 1.0;
+        data[1] = 1.0;
+        //the combs are longer than the buffer, nothing comes back yet
+        reverb.process(&mut data);
+        assert!(data.iter().all(|d| *d == 0.0));
+        assert!(reverb.is_ringing());
+
+        let mut tail = vec![];
+        while reverb.is_ringing() && tail.len() < 20 * SR as usize {
+            let mut data = vec![0.0; 64 * CHANNELS as usize];
+            reverb.process(&mut data);
+            tail.extend(data);
+        }
+        assert!(!reverb.is_ringing(), "the tail never ends");
+        assert!(tail.iter().any(|d| d.abs() > 0.01), "the tail was cut before it came back");
+        reverb.params.enabled = false;
+        assert!(!reverb.is_ringing());
+    }
+
+    #[test]
+    fn delay_rings_until_the_repeats_are_out() {
+        //a half note delay repeats every 4 steps, the steps in between are silent
+        let frames_per_step = 100;
+        let mut delay = Delay::default();
+        delay.set_params(&DelayParams { division: Division::Half, ..DelayParams::default() });
+        let mut exported = vec![];
+        for step in 0..100 {
+            if step > 0 && !delay.is_ringing() {break;}
+            let mut data = vec![0.0; frames_per_step * CHANNELS as usize];
+            if step == 0 {
+                data[0] = 1.0;
+                data[1] = 1.0;
+            }
+            let transport = Transport { frame: (step * frames_per_step) as u64, frames_per_step: frames_per_step as f32 };
+            delay.process(&mut data, &Context { transport, keys: &[] });
+            exported.extend(data);
+        }
+        let peak = |from: usize, to: usize| {
+            exported[from * 2..(to * 2).min(exported.len())].iter().fold(0.0f32, |peak, d| peak.max(d.abs()))
+        };
+        assert!(peak(400, 500) > SILENCE, "first repeat missing");
+        assert!(peak(800, 900) > SILENCE, "second repeat missing");
+    }
+}
@@ -0,0 +1,797 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::app::Messages;
+use crate::effects::{Chain, Context, Effects, Reverb};
+use crate::instr::{Note, PatternNotes, SongEntry, MEAS_COUNT, NOTE_COUNT, STEPS_PER_BAR};
+use crate::lfo::Transport;
+use crate::sampler::{Kit, SampleVoice, Sampler};
+use crate::synth::{MidiNote, ParamLocks, Patch, VoiceKind, CHANNELS, SR};
+use crate::wavetable::Wavetable;
+
+const RECORDING_PATH: &str = "step_sequencer_recording.wav";
+const EXPORT_PATH: &str = "step_sequencer_export.wav";
+const STEM_PATH_PREFIX: &str = "step_sequencer_stem_";
+//stop rendering release tails after this many steps even if voices are still sounding
+const MAX_TAIL_STEPS: usize = 64;
+//voice key of mono tracks, below the lowest note on the grid
+const MONO_VOICE: usize = 0;
+
+enum Trigger {
+    Press(f32, ParamLocks, bool), //velocity, locks, slide
+    Release,
+}
+
+/// Which patterns the engine plays, either looping one pattern or following the song.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arrangement {
+    Loop(usize),
+    Song(Vec<SongEntry>),
+}
+
+/// Where a queued pattern takes over from the playing one.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum SwitchAt {
+    LoopEnd,
+    Bar,
+}
+
+impl std::fmt::Display for SwitchAt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SwitchAt::LoopEnd => write!(f, "Loop end"),
+            SwitchAt::Bar => write!(f, "Next bar"),
+        }
+    }
+}
+
+/// Presses go ahead of releases at the same offset whatever their rows, so a mono voice is
+/// still held when the note it glides to arrives.
+fn trigger_order(trigger: &(usize, usize, Trigger)) -> (usize, bool) {
+    (trigger.0, matches!(trigger.2, Trigger::Release))
+}
+
+/// Voices and note data of a single track.
+struct TrackState {
+    midi_notes: HashMap<usize, MidiNote>,
+    patterns: Vec<Vec<Vec<Note>>>,
+    patch: Patch,
+    sampler: Sampler,
+    kit: Kit,
+    wavetable: Arc<Wavetable>,
+    sliding: bool, //the last mono note slides into the next one
+    held_over: Vec<(usize, usize, Trigger)>, //releases of sliding notes, due at the next step
+    inserts: Chain,
+    reverb_send: f32,
+    mute: bool,
+    solo: bool,
+}
+
+impl Default for TrackState {
+    fn default() -> Self {
+        TrackState {
+            midi_notes: HashMap::new(),
+            patterns: vec![],
+            patch: Patch::default(),
+            sampler: Sampler::default(),
+            kit: Kit::default(),
+            wavetable: Arc::default(),
+            sliding: false,
+            held_over: vec![],
+            inserts: Chain::default(),
+            reverb_send: 0.0,
+            mute: false,
+            solo: false,
+        }
+    }
+}
+
+impl TrackState {
+    fn set_patterns(&mut self, changed_notes: Vec<PatternNotes>) {
+        self.patterns = changed_notes.into_iter().map(|changes| {
+            let mut pattern = vec![vec![Note::default(); MEAS_COUNT]; NOTE_COUNT];
+            for (new_note, i, j) in changes {
+                pattern[i][j] = new_note;
+            }
+            pattern
+        }).collect();
+    }
+
+    fn set_patch(&mut self, patch: Patch) {
+        self.patch = patch;
+        for note in self.midi_notes.values_mut() {
+            note.set_patch(&self.patch);
+        }
+    }
+
+    fn set_effects(&mut self, effects: Effects) {
+        self.inserts.set(&effects.inserts);
+        self.reverb_send = effects.reverb_send;
+    }
+
+    /// Whether any voice or effect tail can still be heard.
+    fn is_sounding(&self) -> bool {
+        self.midi_notes.values().any(|midi_note| midi_note.is_alive()) || self.inserts.is_ringing()
+    }
+
+    fn release_all(&mut self) {
+        for midi_note in self.midi_notes.values_mut() {
+            midi_note.release();
+        }
+    }
+
+    fn step_triggers(&self, pattern: usize, clock: usize, buffer_len: usize) -> Vec<(usize, usize, Trigger)> {
+        let mut triggers = Vec::new();
+        let pattern = match self.patterns.get(pattern) {
+            Some(pattern) => pattern,
+            None => return triggers,
+        };
+        for (ind, row) in pattern.iter().enumerate() {
+            let note = row[clock];
+            let note_num = ind + 21;
+            let mut last_hit = 0;
+            if note.duration != 0 {
+                //ratchets retrigger the voice evenly within the step, gating each hit but the last
+                let hits = note.ratchet.max(1) as usize;
+                for hit in 0..hits {
+                    let offset = hit * buffer_len / hits;
+                    let velocity =
+                        if note.ratchet_ramp {note.velocity * (hit + 1) as f32 / hits as f32}
+                        else {note.velocity};
+                    triggers.push((offset, note_num, Trigger::Press(velocity, note.locks, note.slide)));
+                    if hit + 1 < hits {
+                        triggers.push((offset + buffer_len / (2 * hits), note_num, Trigger::Release));
+                    }
+                    last_hit = offset;
+                }
+            }
+            if let Some(start) = note.starts_at {
+                if row[start].duration + start - 1 == clock {
+                    //a mono note that can glide into the next stays held until the next step has been pressed
+                    let glides = self.patch.legato || row[start].slide;
+                    let offset = if self.patch.mono && glides {buffer_len} else {last_hit};
+                    triggers.push((offset, note_num, Trigger::Release));
+                }
+            }
+        }
+        triggers.sort_by_key(trigger_order);
+        triggers
+    }
+
+    /// Trigger offsets and `buffer_len` count frames, the returned samples are interleaved.
+    fn render(&mut self, mut triggers: Vec<(usize, usize, Trigger)>, buffer_len: usize, transport: Transport) -> Vec<f32> {
+        let channels = CHANNELS as usize;
+        let mut data = vec![0_f32; buffer_len * channels];
+        //held over releases go after this step's first presses, unless the same note is tied on
+        let held_over: Vec<_> = self.held_over.drain(..)
+            .filter(|(_, note_num, _)| !triggers.iter().any(|(offset, other, trigger)| {
+                *offset == 0 && other == note_num && matches!(trigger, Trigger::Press(..))
+            }))
+            .map(|(_, note_num, trigger)| (0, note_num, trigger))
+            .collect();
+        triggers.extend(held_over);
+        triggers.sort_by_key(trigger_order);
+        let mut triggers = triggers.into_iter().peekable();
+        let mut pos = 0;
+        while pos < buffer_len {
+            while let Some((_, note_num, trigger)) = triggers.next_if(|trigger| trigger.0 <= pos) {
+                match trigger {
+                    Trigger::Press(velocity, locks, slide) => {
+                        let patch = &self.patch;
+                        //each grid row plays the kit pad with the same index
+                        let pad = self.kit.pads.get(note_num - 21).filter(|_| patch.kind == VoiceKind::Kit);
+                        if let Some(pad) = pad {
+                            for (_, midi_note) in self.midi_notes.iter_mut().filter(|(n, _)| **n != note_num) {
+                                midi_note.choke(pad.choke);
+                            }
+                        }
+                        //mono tracks play every note on one voice, gliding between pitches
+                        let key = if patch.mono {MONO_VOICE} else {note_num};
+                        let midi_note = self.midi_notes.entry(key).or_insert_with(|| MidiNote::new(note_num, patch));
+                        let legato = patch.mono && midi_note.is_held() && (patch.legato || self.sliding);
+                        if patch.mono {
+                            midi_note.glide_to(note_num, if legato || !patch.legato {patch.glide} else {0.0});
+                            self.sliding = slide;
+                        }
+                        midi_note.set_locks(patch, &locks);
+                        midi_note.set_wavetable(&self.wavetable);
+                        if legato {
+                            continue;
+                        }
+                        midi_note.press();
+                        midi_note.set_velocity(velocity);
+                        match patch.kind {
+                            VoiceKind::Sampler => {
+                                let region = self.sampler.region_for(note_num, velocity);
+                                midi_note.set_sample(region.and_then(|region| SampleVoice::new(region, note_num)));
+                            }
+                            VoiceKind::Kit => {
+                                midi_note.set_sample(pad.and_then(SampleVoice::from_pad));
+                            }
+                            _ => {}
+                        }
+                    }
+                    Trigger::Release => {
+                        //a mono voice that has moved on to another note ignores the old note's release
+                        let key = if self.patch.mono {MONO_VOICE} else {note_num};
+                        if let Some(midi_note) = self.midi_notes.get_mut(&key).filter(|midi_note| midi_note.note() == note_num) {
+                            midi_note.release();
+                        }
+                    }
+                }
+            }
+            let end = triggers.peek().map_or(buffer_len, |trigger| trigger.0);
+            let transport = Transport { frame: transport.frame + pos as u64, ..transport };
+            mix_voices(&mut self.midi_notes, &mut data[pos * channels..end * channels], transport);
+            pos = end;
+        }
+        self.held_over.extend(triggers);
+        data
+    }
+}
+
+/// Sequencer state shared by live playback and offline rendering.
+pub struct Engine {
+    tracks: Vec<TrackState>,
+    arrangement: Arrangement,
+    entry: usize,
+    repeat: usize,
+    clock: usize,
+    tempo: u32,
+    frame: u64,
+    passes: usize,
+    queued: Option<(usize, SwitchAt)>,
+    reverb: Reverb,
+    master: Chain,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            tracks: vec![],
+            arrangement: Arrangement::Loop(0),
+            entry: 0,
+            repeat: 0,
+            clock: 0,
+            tempo: 60,
+            frame: 0,
+            passes: 0,
+            queued: None,
+            reverb: Reverb::default(),
+            master: Chain::default(),
+        }
+    }
+}
+
+impl Engine {
+    /// Applies a message from the UI, playback control is left to the caller.
+    pub fn handle(&mut self, msg: Messages) {
+        match msg {
+            Messages::Play(changed_notes) => {
+                self.tracks.truncate(changed_notes.len());
+                for (track, patterns) in changed_notes.into_iter().enumerate() {
+                    self.track_mut(track).set_patterns(patterns);
+                }
+            }
+            Messages::Stop => {
+                self.reset();
+            }
+            Messages::Record => {}
+            Messages::Tempo(new_tempo) => {
+                self.tempo = new_tempo;
+            }
+            Messages::Patch(track, new_patch) => {
+                self.track_mut(track).set_patch(*new_patch);
+            }
+            Messages::Sampler(track, sampler) => {
+                self.track_mut(track).sampler = sampler;
+            }
+            Messages::Kit(track, kit) => {
+                self.track_mut(track).kit = kit;
+            }
+            Messages::Reverb(params) => {
+                self.reverb.params = params;
+            }
+            Messages::Master(inserts) => {
+                self.master.set(&inserts);
+            }
+            Messages::Effects(track, effects) => {
+                self.track_mut(track).set_effects(effects);
+            }
+            Messages::Wavetable(track, wavetable) => {
+                self.track_mut(track).wavetable = Arc::new(wavetable);
+            }
+            Messages::Arrangement(arrangement) => {
+                self.set_arrangement(arrangement);
+            }
+            Messages::QueuePattern(pattern, switch_at) => {
+                self.queued = Some((pattern, switch_at));
+            }
+            Messages::Mute(track, mute) => {
+                self.track_mut(track).mute = mute;
+                self.release_inaudible();
+            }
+            Messages::Solo(track, solo) => {
+                self.track_mut(track).solo = solo;
+                self.release_inaudible();
+            }
+        }
+    }
+
+    fn track_mut(&mut self, track: usize) -> &mut TrackState {
+        if track >= self.tracks.len() {
+            self.tracks.resize_with(track + 1, TrackState::default);
+        }
+        &mut self.tracks[track]
+    }
+
+    fn set_arrangement(&mut self, arrangement: Arrangement) {
+        self.arrangement = arrangement;
+        self.entry = 0;
+        self.repeat = 0;
+    }
+
+    /// Clears the patterns and rewinds, releasing held voices so they ring out through their release.
+    fn reset(&mut self) {
+        for track in self.tracks.iter_mut() {
+            track.patterns.clear();
+            track.held_over.clear();
+            track.sliding = false;
+            track.release_all();
+        }
+        self.set_arrangement(self.arrangement.clone());
+        self.clock = 0;
+        self.frame = 0;
+        self.passes = 0;
+        self.queued = None;
+    }
+
+    /// Soloed tracks silence every other track, otherwise muted tracks are silent.
+    fn audible(&self) -> Vec<bool> {
+        let any_solo = self.tracks.iter().any(|track| track.solo);
+        self.tracks.iter().map(|track| if any_solo {track.solo} else {!track.mute}).collect()
+    }
+
+    /// Silenced tracks stop triggering and their held voices ring out through their release.
+    fn release_inaudible(&mut self) {
+        let audible = self.audible();
+        for (track, audible) in self.tracks.iter_mut().zip(audible) {
+            if !audible {
+                track.release_all();
+            }
+        }
+    }
+
+    /// Number of times the arrangement has played through to its end.
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    pub fn is_sounding(&self) -> bool {
+        self.tracks.iter().any(|track| track.is_sounding()) || self.reverb.is_ringing() || self.master.is_ringing()
+    }
+
+    pub fn release_all(&mut self) {
+        for track in self.tracks.iter_mut() {
+            track.release_all();
+        }
+    }
+
+    fn step_len(&self) -> usize {
+        (SR as f32 * 0.125 * 60.0/self.tempo as f32).round() as usize
+    }
+
+    pub fn current_pattern(&self) -> Option<usize> {
+        match &self.arrangement {
+            Arrangement::Loop(pattern) => Some(*pattern),
+            Arrangement::Song(entries) => entries.get(self.entry).map(|entry| entry.pattern),
+        }
+    }
+
+    fn advance_arrangement(&mut self) {
+        match &self.arrangement {
+            Arrangement::Loop(_) => {
+                self.passes += 1;
+            }
+            Arrangement::Song(entries) => {
+                self.repeat += 1;
+                if self.repeat >= entries.get(self.entry).map_or(1, |entry| entry.repeats.max(1)) {
+                    self.repeat = 0;
+                    self.entry += 1;
+                    if self.entry >= entries.len() {
+                        self.entry = 0;
+                        self.passes += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Renders one step of the arrangement and advances the clock.
+    pub fn render_step(&mut self) -> Vec<f32> {
+        let tracks = self.render_step_tracks();
+        self.master(tracks)
+    }
+
+    /// Sums rendered track buffers and runs them through the master inserts.
+    fn master(&mut self, tracks: Vec<Vec<f32>>) -> Vec<f32> {
+        let frames_per_step = tracks.first().map_or(0, Vec::len) / CHANNELS as usize;
+        //the engine's frame count has already moved past the step being mixed
+        let transport = Transport { frame: self.frame - frames_per_step as u64, frames_per_step: frames_per_step as f32 };
+        let mut data = mix_tracks(tracks);
+        self.master.process(&mut data, &Context { transport, keys: &[] });
+        data
+    }
+
+    /// Renders one step of the arrangement as one buffer per track followed by the reverb
+    /// return, these sum to the master.
+    pub fn render_step_tracks(&mut self) -> Vec<Vec<f32>> {
+        let buffer_len = self.step_len();
+        let data = self.render(self.current_pattern(), buffer_len);
+        self.clock = (self.clock + 1) % MEAS_COUNT;
+        if self.clock == 0 {
+            self.advance_arrangement();
+        }
+        if let Some((pattern, switch_at)) = self.queued {
+            if self.clock == 0 || (switch_at == SwitchAt::Bar && self.clock % STEPS_PER_BAR == 0) {
+                //notes held over from the old pattern would never see their end step
+                self.release_all();
+                self.set_arrangement(Arrangement::Loop(pattern));
+                self.clock = 0;
+                self.queued = None;
+            }
+        }
+        data
+    }
+
+    /// Renders one step's worth of audio per track and reverb return without triggering any notes.
+    pub fn render_tail_tracks(&mut self) -> Vec<Vec<f32>> {
+        let buffer_len = self.step_len();
+        self.render(None, buffer_len)
+    }
+
+    fn render(&mut self, pattern: Option<usize>, buffer_len: usize) -> Vec<Vec<f32>> {
+        let audible = self.audible();
+        let track_count = self.tracks.len().max(1) as f32;
+        let transport = Transport { frame: self.frame, frames_per_step: buffer_len as f32 };
+        self.frame += buffer_len as u64;
+        let mut buffers: Vec<Vec<f32>> = self.tracks.iter_mut().zip(audible).map(|(track, audible)| {
+            let triggers = match pattern {
+                Some(pattern) if audible => track.step_triggers(pattern, self.clock, buffer_len),
+                _ => vec![],
+            };
+            track.render(triggers, buffer_len, transport)
+        }).collect();
+
+        //tracks go through their inserts after the tracks their sidechains listen to, so keys
+        //hear the processed output, a track in a loop of sidechains keys from the voices instead
+        let mut done = vec![false; buffers.len()];
+        while let Some(first) = done.iter().position(|done| !done) {
+            let i = (first..buffers.len())
+                .filter(|i| !done[*i])
+                .find(|i| self.tracks[*i].inserts.sidechains().all(|source| source == *i || done.get(source).copied().unwrap_or(true)))
+                .unwrap_or(first);
+            //taken out while processing, a compressor keyed from its own track falls back to its input
+            let mut data = std::mem::take(&mut buffers[i]);
+            self.tracks[i].inserts.process(&mut data, &Context { transport, keys: &buffers });
+            buffers[i] = data;
+            done[i] = true;
+        }
+        for data in buffers.iter_mut() {
+            data.iter_mut().for_each(|d| {
+                *d /= track_count;
+            });
+        }
+
+        let mut bus = vec![0.0; buffer_len * CHANNELS as usize];
+        for (track, data) in self.tracks.iter().zip(buffers.iter()) {
+            if track.reverb_send > 0.0 {
+                bus.iter_mut().zip(data).for_each(|(b, d)| {
+                    *b += track.reverb_send * d;
+                });
+            }
+        }
+        self.reverb.process(&mut bus);
+        buffers.push(bus);
+        buffers
+    }
+}
+
+pub fn process_audio (
+    rx: std::sync::mpsc::Receiver<Messages>,
+    playing: Arc<AtomicUsize>,
+) {
+    let (_stream, stream_handle) =
+        rodio::OutputStream::try_default().expect("Could not get output device.");
+    let sink = rodio::Sink::try_new(&stream_handle).expect("Could not get output device.");
+    let mut engine = Engine::default();
+
+    let mut recording = false;
+    let mut recorded_data: Vec<f32> = vec![];
+
+    let mut active = false;
+    loop {
+        if let Ok(msg) = rx.try_recv() {
+            match msg {
+                Messages::Play(_) => {
+                    active = true;
+                    sink.play();
+                }
+                Messages::Stop => {
+                    active = false;
+                    if recording {
+                        export_wav(RECORDING_PATH, recorded_data.clone());
+                        recorded_data.clear();
+                        recording = false;
+                    }
+                }
+                Messages::Record => {
+                    recording = true;
+                }
+                _ => {}
+            }
+            engine.handle(msg);
+        }
+
+        if !active || sink.len() > 2 {continue;}
+
+        playing.store(engine.current_pattern().unwrap_or(0), Ordering::Relaxed);
+        let data = engine.render_step();
+        if recording {recorded_data.extend_from_slice(&data);}
+        sink.append(rodio::buffer::SamplesBuffer::new(CHANNELS, SR, data));
+    }
+}
+
+/// Sets up an engine with the same messages live playback would get, then renders the
+/// arrangement once through plus release tails as one buffer per track, along with the master.
+fn render_song_tracks(setup: Vec<Messages>) -> (Vec<Vec<f32>>, Vec<f32>) {
+    let mut engine = Engine::default();
+    for msg in setup {
+        engine.handle(msg);
+    }
+
+    let mut tracks: Vec<Vec<f32>> = vec![];
+    let mut master = vec![];
+    let mut append = |engine: &mut Engine, block: Vec<Vec<f32>>| {
+        master.extend(engine.master(block.clone()));
+        tracks.resize_with(block.len(), Vec::new);
+        for (track, data) in tracks.iter_mut().zip(block) {
+            track.extend(data);
+        }
+    };
+    while engine.passes() == 0 {
+        let block = engine.render_step_tracks();
+        append(&mut engine, block);
+    }
+    engine.release_all();
+    for _ in 0..MAX_TAIL_STEPS {
+        if !engine.is_sounding() {break;}
+        let block = engine.render_tail_tracks();
+        append(&mut engine, block);
+    }
+    (tracks, master)
+}
+
+/// Renders the arrangement offline and writes the mix to `EXPORT_PATH`.
+pub fn export_song(setup: Vec<Messages>) {
+    export_wav(EXPORT_PATH, render_song_tracks(setup).1);
+}
+
+/// Renders the arrangement offline and writes one equally long file per track, named after
+/// the track, plus the reverb return when it isn't silent and the mix when `with_master` is set.
+pub fn export_stems(setup: Vec<Messages>, track_names: Vec<String>, with_master: bool) {
+    let (tracks, master) = render_song_tracks(setup);
+    let names = track_names.into_iter().chain(std::iter::once("Reverb".to_string()));
+    for (i, (data, name)) in tracks.iter().zip(names).enumerate() {
+        //the reverb return comes last, it is left out when nothing was sent to it
+        if i + 1 == tracks.len() && data.iter().all(|d| *d == 0.0) {
+            continue;
+        }
+        export_wav(&stem_path(i, &name), data.clone());
+    }
+    if with_master {
+        export_wav(&stem_path(tracks.len(), "Master"), master);
+    }
+}
+
+/// Numbered so stems stay in track order and tracks sharing a name don't overwrite each other.
+fn stem_path(index: usize, name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' {c} else {'_'})
+        .collect();
+    format!("{}{:02}_{}.wav", STEM_PATH_PREFIX, index + 1, name)
+}
+
+fn mix_tracks(tracks: Vec<Vec<f32>>) -> Vec<f32> {
+    let mut tracks = tracks.into_iter();
+    let mut data = tracks.next().unwrap_or_default();
+    for track in tracks {
+        data.iter_mut().zip(track).for_each(|(d, t)| {
+            *d += t;
+        });
+    }
+    data
+}
+
+fn mix_voices(midi_notes: &mut HashMap<usize, MidiNote>, data: &mut [f32], transport: Transport) {
+    let mut counter = 0.0;
+    let buffer_len = data.len();
+    for midi_note in midi_notes.values_mut() {
+        if midi_note.is_alive() {
+            midi_note.set_transport(transport);
+            data.iter_mut().zip(midi_note.get_buffer(buffer_len).iter()).for_each(|(d, b)| {
+                *d += b;
+            });
+            counter += 1.0;
+        }
+    }
+    if counter > 0.0 {
+        data.iter_mut().for_each(|d| {
+            *d /= counter;
+        });
+    }
+}
+
+fn export_wav(path: &str, data: Vec<f32>) {
+    let spec = hound::WavSpec {
+        channels: CHANNELS,
+        sample_rate: 44100,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).unwrap();
+    for datum in data {
+        writer.write_sample(datum).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A note on grid `row` from `start` for `duration` steps, laid out the way the grid stores it.
+    fn note(row: usize, start: usize, duration: usize) -> PatternNotes {
+        (start..start + duration).map(|step| {
+            let duration = if step == start {duration} else {0};
+            (Note { duration, starts_at: Some(start), ..Note::default() }, row, step)
+        }).collect()
+    }
+
+    /// Plays one pattern per track.
+    fn play(engine: &mut Engine, tracks: Vec<PatternNotes>) {
+        engine.handle(Messages::Play(tracks.into_iter().map(|notes| vec![notes]).collect()));
+    }
+
+    fn render_steps(engine: &mut Engine, steps: usize) {
+        for _ in 0..steps {
+            engine.render_step();
+        }
+    }
+
+    #[test]
+    fn stop_releases_held_notes() {
+        let mut engine = Engine::default();
+        play(&mut engine, vec![note(40, 0, 16)]);
+        render_steps(&mut engine, 2);
+        engine.handle(Messages::Stop);
+        play(&mut engine, vec![vec![]]);
+        render_steps(&mut engine, 20);
+        assert!(!engine.is_sounding());
+    }
+
+    #[test]
+    fn legato_glides_both_ways() {
+        let patch = Patch { mono: true, legato: true, glide: 0.5, ..Patch::default() };
+        let freq = |row: usize| 440.0 * ((row as f32 + 21.0 - 69.0) / 12.0).exp2();
+        for (from, to) in [(40, 52), (52, 40)] {
+            //overlapping on one step, then touching end to start
+            for notes in [[note(from, 0, 2), note(to, 1, 2)], [note(from, 0, 1), note(to, 1, 1)]] {
+                let mut engine = Engine::default();
+                engine.handle(Messages::Patch(0, Box::new(patch)));
+                play(&mut engine, vec![notes.concat()]);
+                render_steps(&mut engine, 2);
+                let voice = &engine.tracks[0].midi_notes[&MONO_VOICE];
+                assert_eq!(voice.note(), to + 21);
+                assert!(voice.is_held());
+                let (low, high) = (freq(from.min(to)), freq(from.max(to)));
+                assert!(voice.freq() > low && voice.freq() < high, "{} to {} jumped instead of gliding", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn queued_pattern_starts_at_the_loop_end() {
+        let mut engine = Engine::default();
+        engine.handle(Messages::Play(vec![vec![note(40, 0, MEAS_COUNT), vec![]]]));
+        render_steps(&mut engine, 5);
+        engine.handle(Messages::QueuePattern(1, SwitchAt::LoopEnd));
+        render_steps(&mut engine, MEAS_COUNT - 6);
+        assert_eq!(engine.current_pattern(), Some(0));
+        engine.render_step();
+        assert_eq!(engine.current_pattern(), Some(1));
+        assert_eq!(engine.clock, 0);
+        //the old pattern's note never reaches its end step, it is released at the switch
+        assert!(!engine.tracks[0].midi_notes[&61].is_held());
+    }
+
+    #[test]
+    fn queued_pattern_starts_at_the_next_bar() {
+        let mut engine = Engine::default();
+        engine.handle(Messages::Play(vec![vec![note(40, 0, MEAS_COUNT), vec![]]]));
+        render_steps(&mut engine, 5);
+        engine.handle(Messages::QueuePattern(1, SwitchAt::Bar));
+        render_steps(&mut engine, STEPS_PER_BAR - 6);
+        assert_eq!(engine.current_pattern(), Some(0));
+        engine.render_step();
+        assert_eq!(engine.current_pattern(), Some(1));
+        assert_eq!(engine.clock, 0);
+        assert!(!engine.tracks[0].midi_notes[&61].is_held());
+    }
+
+    #[test]
+    fn ratchets_retrigger_evenly_within_the_step() {
+        let mut track = TrackState::default();
+        let ratchet = Note { duration: 1, starts_at: Some(0), velocity: 0.8, ratchet: 4, ratchet_ramp: true, ..Note::default() };
+        track.set_patterns(vec![vec![(ratchet, 40, 0)]]);
+        let triggers: Vec<_> = track.step_triggers(0, 0, 400).into_iter()
+            .map(|(offset, note_num, trigger)| match trigger {
+                Trigger::Press(velocity, ..) => (offset, note_num, Some(velocity)),
+                Trigger::Release => (offset, note_num, None),
+            })
+            .collect();
+        assert_eq!(triggers, [
+            (0, 61, Some(0.2)),
+            (50, 61, None),
+            (100, 61, Some(0.4)),
+            (150, 61, None),
+            (200, 61, Some(0.6)),
+            (250, 61, None),
+            (300, 61, Some(0.8)),
+            (300, 61, None),
+        ]);
+    }
+
+    fn is_silent(data: &[f32]) -> bool {
+        data.iter().all(|d| *d == 0.0)
+    }
+
+    #[test]
+    fn muted_tracks_ring_out_then_stay_silent() {
+        let mut engine = Engine::default();
+        play(&mut engine, vec![note(40, 0, 16), note(44, 0, 16)]);
+        engine.render_step();
+        engine.handle(Messages::Mute(1, true));
+        //released rather than cut, the tail is still rendered
+        let voice = &engine.tracks[1].midi_notes[&65];
+        assert!(voice.is_alive() && !voice.is_held());
+        assert!(!is_silent(&engine.render_step_tracks()[1]));
+        //a whole loop later the muted track has not been triggered again
+        for _ in 0..MEAS_COUNT {
+            engine.render_step_tracks();
+        }
+        let tracks = engine.render_step_tracks();
+        assert!(is_silent(&tracks[1]));
+        assert!(!is_silent(&tracks[0]));
+
+        engine.handle(Messages::Mute(1, false));
+        assert_eq!(engine.audible(), [true, true]);
+    }
+
+    #[test]
+    fn solo_silences_the_other_tracks() {
+        let mut engine = Engine::default();
+        play(&mut engine, vec![note(40, 0, 16), note(44, 0, 16), note(47, 0, 16)]);
+        engine.handle(Messages::Mute(2, true));
+        engine.handle(Messages::Solo(2, true));
+        assert_eq!(engine.audible(), [false, false, true]);
+        let tracks = engine.render_step_tracks();
+        assert!(is_silent(&tracks[0]) && is_silent(&tracks[1]));
+        assert!(!is_silent(&tracks[2]));
+
+        engine.handle(Messages::Solo(2, false));
+        assert_eq!(engine.audible(), [true, true, false]);
+    }
+}
+
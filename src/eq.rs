@@ -0,0 +1,236 @@
+use std::f32::consts::PI;
+
+use crate::effects::{Context, Effect, Param, Placement, SILENCE};
+use crate::synth::{CHANNELS, SR};
+
+pub const BAND_COUNT: usize = 7;
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum BandKind {
+    LowCut,
+    LowShelf,
+    Peak,
+    HighShelf,
+    HighCut,
+}
+
+impl std::fmt::Display for BandKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl BandKind {
+    pub const ALL: [BandKind; 5] = [BandKind::LowCut, BandKind::LowShelf, BandKind::Peak, BandKind::HighShelf, BandKind::HighCut];
+
+    /// Cuts ignore the gain, only their frequency and Q matter.
+    pub fn has_gain(&self) -> bool {
+        !matches!(self, BandKind::LowCut | BandKind::HighCut)
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Band {
+    pub enabled: bool,
+    pub kind: BandKind,
+    pub freq: f32, //in Hz
+    pub gain: f32, //in dB
+    pub q: f32,
+}
+
+impl Default for Band {
+    fn default() -> Self {
+        Band { enabled: true, kind: BandKind::Peak, freq: 1000.0, gain: 0.0, q: 0.707 }
+    }
+}
+
+/// Parametric EQ settings, bands are applied in order.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct EqParams {
+    pub bands: [Band; BAND_COUNT],
+}
+
+impl Default for EqParams {
+    fn default() -> Self {
+        let band = |kind, freq| Band { kind, freq, ..Band::default() };
+        EqParams {
+            bands: [
+                Band { enabled: false, ..band(BandKind::LowCut, 30.0) },
+                band(BandKind::LowShelf, 100.0),
+                band(BandKind::Peak, 400.0),
+                band(BandKind::Peak, 1500.0),
+                band(BandKind::Peak, 5000.0),
+                band(BandKind::HighShelf, 10000.0),
+                Band { enabled: false, ..band(BandKind::HighCut, 18000.0) },
+            ],
+        }
+    }
+}
+
+impl EqParams {
+    /// Gain in dB at `freq` of every enabled band together, for drawing the curve.
+    pub fn response(&self, freq: f32) -> f32 {
+        self.bands.iter()
+            .filter(|band| band.enabled)
+            .map(|band| Biquad::new(band).response(freq))
+            .sum()
+    }
+}
+
+/// Normalized second order filter coefficients, from the RBJ audio EQ cookbook.
+#[derive(Clone, Copy, Debug, Default)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    fn new(band: &Band) -> Biquad {
+        let freq = band.freq.clamp(10.0, 0.49 * SR as f32);
+        let w0 = 2.0 * PI * freq / SR as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q.max(0.1));
+        let a = 10.0f32.powf(band.gain / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let (b, a) = match band.kind {
+            BandKind::LowCut => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            BandKind::HighCut => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            BandKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            BandKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [(a + 1.0) + (a - 1.0) * cos + shelf, -2.0 * ((a - 1.0) + (a + 1.0) * cos), (a + 1.0) + (a - 1.0) * cos - shelf],
+            ),
+            BandKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [(a + 1.0) - (a - 1.0) * cos + shelf, 2.0 * ((a - 1.0) - (a + 1.0) * cos), (a + 1.0) - (a - 1.0) * cos - shelf],
+            ),
+        };
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+        }
+    }
+
+    /// Magnitude in dB at `freq`.
+    fn response(&self, freq: f32) -> f32 {
+        let w = 2.0 * PI * freq / SR as f32;
+        //evaluates the transfer function on the unit circle, z = e^(jw)
+        let (sin1, cos1) = w.sin_cos();
+        let (sin2, cos2) = (2.0 * w).sin_cos();
+        let magnitude = |c: [f32; 3]| {
+            let re = c[0] + c[1] * cos1 + c[2] * cos2;
+            let im = -c[1] * sin1 - c[2] * sin2;
+            re * re + im * im
+        };
+        let num = magnitude(self.b);
+        let den = magnitude([1.0, self.a[0], self.a[1]]);
+        10.0 * (num / den.max(1.0e-12)).max(1.0e-12).log10()
+    }
+}
+
+/// Stereo parametric EQ made of one biquad per band.
+#[derive(Clone, Debug, Default)]
+pub struct Equalizer {
+    params: EqParams,
+    states: [[[f32; 2]; CHANNELS as usize]; BAND_COUNT], //transposed direct form II per band and channel
+}
+
+impl Effect for Equalizer {
+    type Params = EqParams;
+
+    fn set_params(&mut self, params: &EqParams) {
+        self.params = *params;
+    }
+
+    /// A line of controls per band.
+    fn params<'a>(params: &'a mut EqParams, _placement: &Placement<'_>) -> Vec<Param<'a>> {
+        params.bands.iter_mut().flat_map(|band| {
+            let has_gain = band.kind.has_gain();
+            [
+                Param::toggle("", &mut band.enabled),
+                Param::options("", &mut band.kind, &BandKind::ALL).same_line(),
+                Param::float("", &mut band.freq, 20.0..=20000.0, " Hz").logarithmic().same_line(),
+                Param::float("", &mut band.gain, -24.0..=24.0, " dB").enabled(has_gain).same_line(),
+                Param::float("Q", &mut band.q, 0.1..=18.0, "").logarithmic().same_line(),
+            ]
+        }).collect()
+    }
+
+    fn process(&mut self, data: &mut [f32], _context: &Context<'_>) {
+        let channels = CHANNELS as usize;
+        for (band, states) in self.params.bands.iter().zip(self.states.iter_mut()) {
+            //a disabled band is bypassed and starts from rest when re-enabled, a flat one keeps
+            //running at unity so it doesn't click while its gain moves through zero
+            if !band.enabled {
+                *states = Default::default();
+                continue;
+            }
+            let Biquad { b, a } = Biquad::new(band);
+            for frame in data.chunks_mut(channels) {
+                for (sample, state) in frame.iter_mut().zip(states.iter_mut()) {
+                    let input = *sample;
+                    let output = b[0] * input + state[0];
+                    state[0] = b[1] * input - a[0] * output + state[1];
+                    state[1] = b[2] * input - a[1] * output;
+                    *sample = output;
+                }
+            }
+        }
+    }
+
+    /// Whether a band, resonant ones especially, still holds anything audible.
+    fn is_ringing(&self) -> bool {
+        self.states.iter().flatten().flatten().any(|state| state.abs() > SILENCE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(kind: BandKind, gain: f32, freq: f32) -> f32 {
+        Biquad::new(&Band { kind, freq: 1000.0, gain, ..Band::default() }).response(freq)
+    }
+
+    #[test]
+    fn bands_have_their_gain_where_they_act() {
+        assert!((response(BandKind::Peak, 6.0, 1000.0) - 6.0).abs() < 0.01);
+        assert!(response(BandKind::Peak, 6.0, 50.0).abs() < 0.1);
+        assert!((response(BandKind::LowShelf, -9.0, 20.0) + 9.0).abs() < 0.1);
+        assert!(response(BandKind::LowShelf, -9.0, 15000.0).abs() < 0.1);
+        assert!((response(BandKind::HighShelf, 4.0, 15000.0) - 4.0).abs() < 0.1);
+        assert!((response(BandKind::LowCut, 0.0, 1000.0) + 3.01).abs() < 0.05);
+        assert!(response(BandKind::LowCut, 0.0, 50.0) < -40.0);
+        assert!(response(BandKind::HighCut, 0.0, 15000.0) < -20.0);
+        assert!(response(BandKind::Peak, 0.0, 300.0).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn disabled_bands_leave_the_curve_flat() {
+        let mut eq = EqParams::default();
+        eq.bands[2].gain = 12.0;
+        eq.bands[2].enabled = false;
+        for freq in [30.0, 400.0, 5000.0, 16000.0] {
+            assert!(eq.response(freq).abs() < 1.0e-3);
+        }
+    }
+}
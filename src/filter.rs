@@ -0,0 +1,74 @@
+use std::f32::consts::PI;
+
+use crate::synth::{Adsr, SR};
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum FilterMode {
+    Off,
+    LowPass,
+    HighPass,
+    BandPass,
+}
+
+impl std::fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Filter settings of a patch, the envelope sweeps the cutoff by `env_amount` octaves.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Filter {
+    pub mode: FilterMode,
+    pub cutoff: f32, //in Hz
+    pub resonance: f32, //0.0 to 1.0, self-oscillates near 1.0
+    pub env_amount: f32,
+    pub envelope: Adsr,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            mode: FilterMode::Off,
+            cutoff: 2000.0,
+            resonance: 0.2,
+            env_amount: 0.0,
+            envelope: Adsr::default(),
+        }
+    }
+}
+
+/// Topology-preserving state-variable filter, stable while the cutoff is modulated per sample.
+#[derive(Clone, Debug, Default)]
+pub struct Svf {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    pub fn process(&mut self, input: f32, mode: FilterMode, cutoff: f32, resonance: f32) -> f32 {
+        if mode == FilterMode::Off {
+            return input;
+        }
+        let cutoff = cutoff.clamp(20.0, 0.45 * SR as f32);
+        let g = (PI * cutoff / SR as f32).tan();
+        let k = 2.0 - 2.0 * resonance.clamp(0.0, 0.98);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        match mode {
+            FilterMode::Off => input,
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => input - k * v1 - v2,
+            FilterMode::BandPass => v1,
+        }
+    }
+}
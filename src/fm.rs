@@ -0,0 +1,168 @@
+use std::f32::consts::PI;
+
+use crate::synth::{Adsr, Envelope, SR};
+
+pub const OPERATOR_COUNT: usize = 4;
+//phase deviation in radians of a modulator at full level
+const MOD_DEPTH: f32 = 2.0 * PI;
+
+/// How the operators feed each other, the highest numbered operator sits at the top.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum Algorithm {
+    Stack, //4 > 3 > 2 > 1
+    Pairs, //2 > 1 and 4 > 3
+    Fan, //2, 3 and 4 all > 1
+    Additive, //every operator is heard
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl Algorithm {
+    fn modulates(&self, from: usize, to: usize) -> bool {
+        match self {
+            Algorithm::Stack => from == to + 1,
+            Algorithm::Pairs => from == to + 1 && to % 2 == 0,
+            Algorithm::Fan => to == 0 && from > 0,
+            Algorithm::Additive => false,
+        }
+    }
+
+    fn is_carrier(&self, op: usize) -> bool {
+        match self {
+            Algorithm::Stack | Algorithm::Fan => op == 0,
+            Algorithm::Pairs => op % 2 == 0,
+            Algorithm::Additive => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Operator {
+    pub ratio: f32, //frequency relative to the note
+    pub level: f32, //output for carriers, modulation index for modulators
+    pub envelope: Adsr,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Operator { ratio: 1.0, level: 0.0, envelope: Adsr::default() }
+    }
+}
+
+/// Settings of the FM voice, only the first `operators` of `ops` are used.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct FmParams {
+    pub operators: usize,
+    pub algorithm: Algorithm,
+    pub feedback: f32, //self-modulation of the top operator
+    pub ops: [Operator; OPERATOR_COUNT],
+}
+
+impl Default for FmParams {
+    fn default() -> Self {
+        let mut ops = [Operator::default(); OPERATOR_COUNT];
+        ops[0].level = 1.0;
+        ops[1].level = 0.5;
+        ops[2].ratio = 2.0;
+        ops[3].ratio = 3.0;
+        FmParams { operators: 2, algorithm: Algorithm::Stack, feedback: 0.0, ops }
+    }
+}
+
+/// Phase-modulation operators sharing one pitch, each with its own envelope.
+#[derive(Clone, Debug)]
+pub struct FmVoice {
+    phases: [f32; OPERATOR_COUNT],
+    envelopes: [Envelope; OPERATOR_COUNT],
+    history: [f32; 2], //last outputs of the top operator, averaged for feedback
+}
+
+impl FmVoice {
+    pub fn new() -> FmVoice {
+        FmVoice {
+            phases: [0.0; OPERATOR_COUNT],
+            envelopes: std::array::from_fn(|_| Envelope::new()),
+            history: [0.0; 2],
+        }
+    }
+
+    pub fn press(&mut self) {
+        for envelope in self.envelopes.iter_mut() {
+            envelope.press();
+        }
+    }
+
+    pub fn release(&mut self) {
+        for envelope in self.envelopes.iter_mut() {
+            envelope.release();
+        }
+    }
+
+    /// Alive while any carrier can still be heard.
+    pub fn is_alive(&self, params: &FmParams) -> bool {
+        (0..operator_count(params)).any(|op| params.algorithm.is_carrier(op) && self.envelopes[op].is_alive())
+    }
+
+    pub fn next(&mut self, params: &FmParams, freq: f32) -> f32 {
+        let count = operator_count(params);
+        let top = count - 1;
+        let mut outputs = [0.0; OPERATOR_COUNT];
+        for op in (0..count).rev() {
+            let mut input: f32 = (op + 1..count)
+                .filter(|from| params.algorithm.modulates(*from, op))
+                .map(|from| outputs[from] * MOD_DEPTH)
+                .sum();
+            if op == top {
+                input += params.feedback * PI * (self.history[0] + self.history[1]) / 2.0;
+            }
+            let settings = &params.ops[op];
+            let level = settings.level * self.envelopes[op].next(&settings.envelope);
+            outputs[op] = level * (self.phases[op] + input).sin();
+            self.phases[op] = (self.phases[op] + 2.0 * PI * freq * settings.ratio / SR as f32) % (2.0 * PI);
+        }
+        self.history = [self.history[1], outputs[top]];
+
+        let carriers = (0..count).filter(|op| params.algorithm.is_carrier(*op));
+        let (sum, heard) = carriers.fold((0.0, 0), |(sum, heard), op| (sum + outputs[op], heard + 1));
+        sum / heard.max(1) as f32
+    }
+}
+
+fn operator_count(params: &FmParams) -> usize {
+    params.operators.clamp(2, OPERATOR_COUNT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing(algorithm: Algorithm) -> Vec<(usize, usize)> {
+        (0..OPERATOR_COUNT)
+            .flat_map(|to| (0..OPERATOR_COUNT).map(move |from| (from, to)))
+            .filter(|(from, to)| algorithm.modulates(*from, *to))
+            .collect()
+    }
+
+    #[test]
+    fn algorithms_route_as_drawn() {
+        assert_eq!(routing(Algorithm::Stack), [(1, 0), (2, 1), (3, 2)]);
+        assert_eq!(routing(Algorithm::Pairs), [(1, 0), (3, 2)]);
+        assert_eq!(routing(Algorithm::Fan), [(1, 0), (2, 0), (3, 0)]);
+        assert_eq!(routing(Algorithm::Additive), []);
+    }
+
+    #[test]
+    fn carriers_are_not_modulators() {
+        for algorithm in [Algorithm::Stack, Algorithm::Pairs, Algorithm::Fan, Algorithm::Additive] {
+            for (from, _) in routing(algorithm) {
+                assert!(!algorithm.is_carrier(from), "{} operator {} is both", algorithm, from);
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::sampler::{Kit, Pad, Region, SampleRef, Sampler};
+
+/// Reads a Hydrogen `drumkit.xml` into a kit with one pad per instrument and starts loading
+/// its samples. Pads only play one sample, so instruments with velocity layers keep their
+/// loudest layer.
+pub fn import_hydrogen(path: &str) -> Result<Kit, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let doc = roxmltree::Document::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let root = doc.root_element();
+
+    let mut kit = Kit { name: child_text(root, "name").unwrap_or_default().to_string(), pads: vec![] };
+    let instruments = root.children()
+        .filter(|node| node.tag_name().name() == "instrumentList")
+        .flat_map(|list| list.children().filter(|node| node.tag_name().name() == "instrument"));
+    for instrument in instruments {
+        let layer = instrument.descendants()
+            .filter(|node| node.tag_name().name() == "layer")
+            .max_by(|a, b| child_f32(*a, "max").unwrap_or(1.0).total_cmp(&child_f32(*b, "max").unwrap_or(1.0)));
+        //kits from before velocity layers name the file on the instrument itself
+        let filename = match layer.and_then(|layer| child_text(layer, "filename")) {
+            Some(filename) => filename,
+            None => match child_text(instrument, "filename") {
+                Some(filename) => filename,
+                None => continue,
+            },
+        };
+        let layer_value = |name, default| layer.and_then(|layer| child_f32(layer, name)).unwrap_or(default);
+        //newer kits store a single pan, older ones a gain per side
+        let pan = child_f32(instrument, "pan").unwrap_or_else(|| {
+            child_f32(instrument, "pan_R").unwrap_or(1.0) - child_f32(instrument, "pan_L").unwrap_or(1.0)
+        });
+        let choke = child_f32(instrument, "muteGroup").unwrap_or(-1.0);
+        kit.pads.push(Pad {
+            name: child_text(instrument, "name").unwrap_or("Pad").to_string(),
+            sample: SampleRef::new(dir.join(filename).to_string_lossy().into_owned()),
+            volume: (child_f32(instrument, "volume").unwrap_or(1.0) * layer_value("gain", 1.0)).clamp(0.0, 1.0),
+            pan: pan.clamp(-1.0, 1.0),
+            tune: child_f32(instrument, "pitchOffset").unwrap_or(0.0) + layer_value("pitch", 0.0),
+            choke: if choke < 0.0 {0} else {(choke as u8).saturating_add(1)},
+        });
+    }
+    kit.load_all();
+    Ok(kit)
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.tag_name().name() == name)
+        .and_then(|child| child.text())
+        .map(str::trim)
+}
+
+fn child_f32(node: roxmltree::Node<'_, '_>, name: &str) -> Option<f32> {
+    child_text(node, name).and_then(|text| text.parse().ok())
+}
+
+/// Reads the regions of an SFZ instrument: samples with key and velocity ranges, root key,
+/// key tracking, loop mode and loop points, then starts loading the samples. Opcodes set under
+/// `<global>`, `<master>` and `<group>` headers apply to the regions below them, anything else
+/// is ignored.
+pub fn import_sfz(path: &str) -> Result<Sampler, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+
+    let mut default_path = String::new();
+    let mut sampler = Sampler::default();
+    //global, master, group and region opcodes, a header clears its own level and those below it
+    let mut levels: [HashMap<String, String>; 4] = Default::default();
+    let mut level = None;
+    let mut last_key = None;
+    for token in tokenize_sfz(&text) {
+        if let Some(header) = token.strip_prefix('<').and_then(|token| token.strip_suffix('>')) {
+            if level == Some(3) {
+                sampler.regions.push(sfz_region(&levels, dir, &default_path));
+            }
+            level = match header {
+                "global" => Some(0),
+                "master" => Some(1),
+                "group" => Some(2),
+                "region" => Some(3),
+                _ => None,
+            };
+            if let Some(level) = level {
+                for opcodes in levels[level..].iter_mut() {
+                    opcodes.clear();
+                }
+            }
+            last_key = None;
+        }
+        else if let Some((key, value)) = token.split_once('=') {
+            if key == "default_path" {
+                default_path = value.to_string();
+            }
+            else if let Some(level) = level {
+                levels[level].insert(key.to_string(), value.to_string());
+            }
+            last_key = Some(key.to_string());
+        }
+        //values such as sample paths may contain spaces
+        else if let (Some(level), Some(key)) = (level, &last_key) {
+            if let Some(value) = levels[level].get_mut(key) {
+                value.push(' ');
+                value.push_str(&token);
+            }
+        }
+    }
+    if level == Some(3) {
+        sampler.regions.push(sfz_region(&levels, dir, &default_path));
+    }
+    sampler.regions.retain(|region| !region.sample.path.is_empty());
+    sampler.load_all();
+    Ok(sampler)
+}
+
+/// Splits SFZ text into headers and `opcode=value` words, without comments or preprocessor lines.
+fn tokenize_sfz(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split("//").next().unwrap_or_default().trim())
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| {
+            line.replace('<', " <").replace('>', "> ")
+                .split_whitespace()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn sfz_region(levels: &[HashMap<String, String>; 4], dir: &Path, default_path: &str) -> Region {
+    //the most specific header wins
+    let opcode = |name: &str| levels.iter().rev().find_map(|opcodes| opcodes.get(name)).map(String::as_str);
+    let key = |name: &str| opcode(name).and_then(parse_key);
+    let number = |name: &str| opcode(name).and_then(|value| value.parse::<f64>().ok());
+
+    let mut region = Region::default();
+    if let Some(sample) = opcode("sample") {
+        let sample = format!("{}{}", default_path, sample).replace('\\', "/");
+        region.sample = SampleRef::new(dir.join(sample).to_string_lossy().into_owned());
+    }
+    if let Some(key) = key("key") {
+        region.lo_key = key;
+        region.hi_key = key;
+        region.root = key;
+    }
+    region.lo_key = key("lokey").unwrap_or(region.lo_key);
+    region.hi_key = key("hikey").unwrap_or(region.hi_key).max(region.lo_key);
+    region.root = key("pitch_keycenter").unwrap_or(region.root);
+    region.lo_vel = number("lovel").map_or(region.lo_vel, |vel| vel.clamp(0.0, 127.0) as u8);
+    region.hi_vel = number("hivel").map_or(region.hi_vel, |vel| vel.clamp(0.0, 127.0) as u8).max(region.lo_vel);
+    region.pitched = number("pitch_keytrack") != Some(0.0);
+    match opcode("loop_mode").or_else(|| opcode("loopmode")) {
+        Some("one_shot") => region.one_shot = true,
+        Some("loop_continuous") | Some("loop_sustain") => {
+            let start = number("loop_start").or_else(|| number("loopstart")).unwrap_or(0.0);
+            //SFZ loop ends are inclusive
+            region.loop_points = number("loop_end").or_else(|| number("loopend"))
+                .map(|end| (start.max(0.0) as usize, end.max(0.0) as usize + 1));
+        }
+        _ => {}
+    }
+    region
+}
+
+/// MIDI note number, or a note name such as `c#4` where `c4` is middle C.
+fn parse_key(value: &str) -> Option<usize> {
+    if let Ok(key) = value.parse::<usize>() {
+        return Some(key.min(127));
+    }
+    let value = value.to_lowercase();
+    let mut chars = value.chars();
+    let mut semitone: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave = if let Some(rest) = rest.strip_prefix('#') {
+        semitone += 1;
+        rest
+    }
+    else if let Some(rest) = rest.strip_prefix('b').filter(|rest| !rest.is_empty()) {
+        semitone -= 1;
+        rest
+    }
+    else {
+        rest
+    };
+    let key = (octave.parse::<i32>().ok()? + 1) * 12 + semitone;
+    Some(key.clamp(0, 127) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_parse_as_numbers_or_note_names() {
+        assert_eq!(parse_key("60"), Some(60));
+        assert_eq!(parse_key("200"), Some(127));
+        assert_eq!(parse_key("c4"), Some(60));
+        assert_eq!(parse_key("C#4"), Some(61));
+        assert_eq!(parse_key("eb4"), Some(63));
+        assert_eq!(parse_key("b3"), Some(59));
+        assert_eq!(parse_key("bb3"), Some(58));
+        assert_eq!(parse_key("c-1"), Some(0));
+        assert_eq!(parse_key("h4"), None);
+        assert_eq!(parse_key("c"), None);
+    }
+
+    #[test]
+    fn sfz_splits_into_headers_and_opcodes() {
+        let text = "#define $KEY 60\n<group>lovel=1 // soft layer\n<region>sample=a.wav key=$KEY<region> sample=b.wav";
+        assert_eq!(tokenize_sfz(text), [
+            "<group>", "lovel=1", "<region>", "sample=a.wav", "key=$KEY", "<region>", "sample=b.wav",
+        ]);
+    }
+}
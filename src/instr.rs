@@ -1,16 +1,45 @@
+use crate::effects::Effects;
+use crate::sampler::{Kit, Sampler};
+use crate::wavetable::Wavetable;
+use crate::synth::{ParamLocks, Patch};
+
 pub const MEAS_COUNT: usize = 32;
 pub const NOTE_COUNT: usize = 107;
+pub const STEPS_PER_BAR: usize = 8;
+pub const MAX_RATCHET: u8 = 8;
+pub const MAX_PATTERNS: usize = 26;
+
+pub type PatternNotes = Vec<(Note, usize, usize)>;
 
 
 #[derive(Clone, Copy, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
 pub struct Note {
     pub duration: usize, //in eighth notes
     pub starts_at: Option<usize>,
+    pub velocity: f32, //0.0 to 1.0, scales the level and picks sampler velocity layers
+    pub ratchet: u8, //number of evenly spaced hits within the first step
+    pub ratchet_ramp: bool, //ramp hit velocities up towards the last hit
+    pub slide: bool, //on mono tracks, glide into the next note without re-attacking
+    pub locks: ParamLocks,
 } 
 
 impl Default for Note {
     fn default() -> Self {
-        Note { duration: 0, starts_at: None }
+        Note { duration: 0, starts_at: None, velocity: 1.0, ratchet: 1, ratchet_ramp: false, slide: false, locks: ParamLocks::default() }
+    }
+}
+
+/// One slot of the song arrangement, playing a pattern a number of times in a row.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct SongEntry {
+    pub pattern: usize,
+    pub repeats: usize,
+}
+
+impl Default for SongEntry {
+    fn default() -> Self {
+        SongEntry { pattern: 0, repeats: 1 }
     }
 }
 
@@ -28,6 +57,20 @@ impl Instrument {
     pub fn get_note_mut(&mut self, i: usize, j: usize) -> &mut Note{
         &mut self.notes[i][j]
     }
+
+    /// Every note that differs from the default, with its row and step.
+    pub fn get_changed_notes(&self) -> PatternNotes {
+        let mut changed_notes = Vec::new();
+        for i in 0..NOTE_COUNT {
+            for j in 0..MEAS_COUNT {
+                let note = self.get_note(i, j);
+                if note != Note::default() {
+                    changed_notes.push((note, i, j));
+                }
+            }
+        }
+        changed_notes
+    }
     
 }
 
@@ -40,6 +83,48 @@ impl Default for Instrument {
     }
 }
 
+/// A part with its own sound and one grid per pattern, every track has the same pattern count.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Track {
+    pub name: String,
+    pub patch: Patch,
+    pub sampler: Sampler,
+    pub kit: Kit,
+    pub wavetable: Wavetable,
+    pub effects: Effects,
+    pub patterns: Vec<Instrument>,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+impl Track {
+    pub fn new(name: String, pattern_count: usize) -> Track {
+        Track {
+            name,
+            patch: Patch::default(),
+            sampler: Sampler::default(),
+            kit: Kit::default(),
+            wavetable: Wavetable::default(),
+            effects: Effects::default(),
+            patterns: (0..pattern_count).map(|_| Instrument::default()).collect(),
+            mute: false,
+            solo: false,
+        }
+    }
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Track::new("Track 1".to_string(), 1)
+    }
+}
+
+/// Patterns are named A, B, C... in the order they were added.
+pub fn pattern_name(pattern: usize) -> String {
+    ((b'A' + (pattern % MAX_PATTERNS) as u8) as char).to_string()
+}
+
 pub fn note_num_to_str(note_num: usize) -> String {
     (match note_num {
         127 => {"G9"}
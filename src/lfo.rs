@@ -0,0 +1,158 @@
+use std::f32::consts::PI;
+
+use crate::synth::SR;
+
+pub const LFO_COUNT: usize = 2;
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Square,
+    SampleAndHold,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum LfoTarget {
+    Pitch,
+    Cutoff,
+    Amplitude,
+    Pan,
+    WavePosition,
+    PulseWidth,
+}
+
+impl LfoTarget {
+    /// Largest depth that makes sense for the target, in the units `LfoTarget::unit` names.
+    pub fn max_depth(&self) -> f32 {
+        match self {
+            LfoTarget::Pitch => 12.0,
+            LfoTarget::Cutoff => 8.0,
+            LfoTarget::Amplitude => 1.0,
+            LfoTarget::Pan => 1.0,
+            LfoTarget::WavePosition => 1.0,
+            LfoTarget::PulseWidth => 0.5,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            LfoTarget::Pitch => " st",
+            LfoTarget::Cutoff => " oct",
+            LfoTarget::Amplitude => "",
+            LfoTarget::Pan => "",
+            LfoTarget::WavePosition => "",
+            LfoTarget::PulseWidth => "",
+        }
+    }
+}
+
+impl std::fmt::Display for LfoShape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::fmt::Display for LfoTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A modulation source shared by every voice of a track. Its phase follows the transport
+/// rather than the note, so it keeps running across notes.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Lfo {
+    pub shape: LfoShape,
+    pub target: LfoTarget,
+    pub depth: f32,
+    pub rate: f32, //in Hz when free-running
+    pub sync: bool,
+    pub steps: u32, //cycle length in sequencer steps when synced
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Lfo {
+            shape: LfoShape::Sine,
+            target: LfoTarget::Pitch,
+            depth: 0.0,
+            rate: 5.0,
+            sync: false,
+            steps: 8,
+        }
+    }
+}
+
+/// Playback position handed to voices so modulation can follow the sequencer clock.
+#[derive(Clone, Copy, Debug)]
+pub struct Transport {
+    pub frame: u64,
+    pub frames_per_step: f32,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport { frame: 0, frames_per_step: 1.0 }
+    }
+}
+
+/// Summed LFO output per target for one frame.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Modulation {
+    pub pitch: f32, //semitones
+    pub cutoff: f32, //octaves
+    pub amplitude: f32, //gain multiplier offset, at most 0
+    pub pan: f32,
+    pub position: f32, //wavetable frames, 1.0 spans the table
+    pub width: f32, //pulse duty cycle
+}
+
+impl Lfo {
+    /// Cycles completed at the transport position, the fraction is the phase.
+    pub fn cycles(&self, transport: &Transport) -> f64 {
+        if self.sync {
+            transport.frame as f64 / (transport.frames_per_step as f64 * self.steps.max(1) as f64)
+        }
+        else {
+            transport.frame as f64 * self.rate as f64 / SR as f64
+        }
+    }
+
+    /// Bipolar value between -1.0 and 1.0.
+    pub fn value(&self, transport: &Transport) -> f32 {
+        let cycles = self.cycles(transport);
+        let phase = cycles.fract() as f32;
+        match self.shape {
+            LfoShape::Sine => (2.0 * PI * phase).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Square => if phase < 0.5 {1.0} else {-1.0},
+            LfoShape::SampleAndHold => random(cycles.floor() as u64),
+        }
+    }
+
+    pub fn modulate(&self, modulation: &mut Modulation, transport: &Transport) {
+        if self.depth == 0.0 {
+            return;
+        }
+        let value = self.value(transport);
+        match self.target {
+            LfoTarget::Pitch => modulation.pitch += self.depth * value,
+            LfoTarget::Cutoff => modulation.cutoff += self.depth * value,
+            LfoTarget::Amplitude => modulation.amplitude -= self.depth * (0.5 - 0.5 * value),
+            LfoTarget::Pan => modulation.pan += self.depth * value,
+            LfoTarget::WavePosition => modulation.position += self.depth * value,
+            LfoTarget::PulseWidth => modulation.width += self.depth * value,
+        }
+    }
+}
+
+/// Hashes the cycle number so every voice holds the same random value for a cycle.
+fn random(seed: u64) -> f32 {
+    let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x ^= x >> 31;
+    x = x.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x ^= x >> 29;
+    (x >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+}
@@ -1,6 +1,16 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod effects;
+mod engine;
+mod eq;
+mod filter;
+mod fm;
+mod import;
 mod instr;
+mod lfo;
+mod perc;
+mod sampler;
 mod synth;
+mod wavetable;
 pub use app::StepSequencer;
@@ -0,0 +1,87 @@
+use std::f32::consts::PI;
+
+use crate::effects::SILENCE;
+use crate::filter::{FilterMode, Svf};
+use crate::synth::{Noise, SR};
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum Drum {
+    Kick,
+    Snare,
+    Hat,
+}
+
+/// Settings shared by the percussion voices, what `tone` shapes depends on the drum.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct DrumParams {
+    pub decay: f32, //in seconds
+    pub tone: f32, //kick click, snare body against noise, hat brightness
+    pub sweep: f32, //kick pitch drop in octaves
+}
+
+impl Default for DrumParams {
+    fn default() -> Self {
+        DrumParams { decay: 0.3, tone: 0.5, sweep: 2.0 }
+    }
+}
+
+/// One-shot percussion source, retriggered on every press and silent once it has decayed.
+#[derive(Clone, Debug)]
+pub struct DrumVoice {
+    time: f32,
+    phase: f32,
+    level: f32,
+    noise: Noise,
+    svf: Svf,
+}
+
+impl DrumVoice {
+    pub fn new() -> DrumVoice {
+        DrumVoice { time: f32::MAX, phase: 0.0, level: 0.0, noise: Noise::default(), svf: Svf::default() }
+    }
+
+    pub fn trigger(&mut self) {
+        self.time = 0.0;
+        self.phase = 0.0;
+        self.level = 1.0;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.level > SILENCE
+    }
+
+    /// `freq` is the pitch of the grid row, drums are tuned so that C4 plays them at their root.
+    pub fn next(&mut self, drum: Drum, params: &DrumParams, freq: f32) -> f32 {
+        if !self.is_alive() {
+            return 0.0;
+        }
+        let tune = freq / 261.63;
+        let t = self.time;
+        self.time += 1.0 / SR as f32;
+        let decay = params.decay.max(0.005);
+        self.level = (-t / decay).exp();
+
+        match drum {
+            Drum::Kick => {
+                let end = 50.0 * tune;
+                let freq = end * (params.sweep * (-t / 0.03).exp()).exp2();
+                self.phase = (self.phase + 2.0 * PI * freq / SR as f32) % (2.0 * PI);
+                let click = params.tone * (-t / 0.002).exp() * self.noise.white();
+                self.level * self.phase.sin() + click
+            }
+            Drum::Snare => {
+                let freq = 180.0 * tune;
+                self.phase = (self.phase + 2.0 * PI * freq / SR as f32) % (2.0 * PI);
+                //the body dies away faster than the noise, but never outlasts the voice
+                let body = self.level * (-t / 0.08).exp() * self.phase.sin();
+                let noise = self.svf.process(self.noise.white(), FilterMode::HighPass, 1200.0 * tune, 0.1);
+                params.tone * body + (1.0 - params.tone) * self.level * noise
+            }
+            Drum::Hat => {
+                let cutoff = (4000.0 + 8000.0 * params.tone) * tune;
+                self.level * self.svf.process(self.noise.white(), FilterMode::HighPass, cutoff, 0.3)
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rodio::Source;
+
+use crate::synth::SR;
+
+//length of the fade when a choke group cuts a pad off, in seconds
+const CHOKE_TIME: f32 = 0.005;
+
+/// Decoded audio, mixed down to mono.
+#[derive(Debug)]
+pub struct Sample {
+    pub data: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// Decodes a WAV, FLAC or Ogg Vorbis file with rodio.
+pub fn load_sample(path: &str) -> Result<Sample, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let decoder = rodio::Decoder::new(BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))?;
+    let channels = decoder.channels().max(1) as usize;
+    let sample_rate = decoder.sample_rate();
+    let interleaved: Vec<f32> = decoder.convert_samples().collect();
+    let data = interleaved.chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok(Sample { data, sample_rate })
+}
+
+//result of a decode running on another thread, filled in once it finishes
+type Pending = Arc<Mutex<Option<Result<Sample, String>>>>;
+
+/// A sample file on disk. Only the path is saved, the audio is loaded again on startup.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SampleRef {
+    pub path: String,
+    #[serde(skip)]
+    pub data: Option<Arc<Sample>>,
+    #[serde(skip)]
+    pending: Option<Pending>,
+}
+
+impl PartialEq for SampleRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && match (&self.data, &other.data) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl SampleRef {
+    pub fn new(path: String) -> SampleRef {
+        SampleRef { path, data: None, pending: None }
+    }
+
+    /// Decodes the file on a background thread so the UI doesn't stall, `poll` picks up
+    /// the result. The current audio stays in place until then.
+    pub fn load(&mut self) {
+        let pending = Pending::default();
+        let result = pending.clone();
+        let path = self.path.clone();
+        std::thread::spawn(move || {
+            let sample = load_sample(&path);
+            *result.lock().unwrap() = Some(sample);
+        });
+        self.pending = Some(pending);
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Takes the audio of a finished load, `None` while nothing has finished.
+    pub fn poll(&mut self) -> Option<Result<(), String>> {
+        let result = self.pending.as_ref()?.lock().unwrap().take()?;
+        self.pending = None;
+        Some(result.map(|sample| self.data = Some(Arc::new(sample))))
+    }
+}
+
+/// Starts loading every sample that isn't loaded or loading yet.
+fn load_all<'a>(samples: impl Iterator<Item = &'a mut SampleRef>) {
+    samples
+        .filter(|sample| sample.data.is_none() && !sample.is_loading() && !sample.path.is_empty())
+        .for_each(SampleRef::load);
+}
+
+/// Picks up finished loads, adding failures to `errors`. Returns whether any audio changed.
+fn poll_all<'a>(samples: impl Iterator<Item = &'a mut SampleRef>, errors: &mut Vec<String>) -> bool {
+    let mut loaded = false;
+    for result in samples.filter_map(SampleRef::poll) {
+        match result {
+            Ok(()) => loaded = true,
+            Err(error) => errors.push(error),
+        }
+    }
+    loaded
+}
+
+/// A sample mapped onto a range of keys and velocities.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Region {
+    pub sample: SampleRef,
+    pub root: usize, //note number the sample plays back unchanged at
+    pub lo_key: usize,
+    pub hi_key: usize,
+    pub lo_vel: u8,
+    pub hi_vel: u8,
+    pub loop_points: Option<(usize, usize)>, //start and end frame, looped while the note is held
+    pub pitched: bool, //follow the grid row relative to `root`
+    pub one_shot: bool, //play to the end regardless of note length
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Region {
+            sample: SampleRef::default(),
+            root: 60,
+            lo_key: 0,
+            hi_key: 127,
+            lo_vel: 0,
+            hi_vel: 127,
+            loop_points: None,
+            pitched: true,
+            one_shot: false,
+        }
+    }
+}
+
+/// Sample playback settings of a track.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Sampler {
+    pub regions: Vec<Region>,
+}
+
+impl Sampler {
+    pub fn load_all(&mut self) {
+        load_all(self.regions.iter_mut().map(|region| &mut region.sample));
+    }
+
+    pub fn poll(&mut self, errors: &mut Vec<String>) -> bool {
+        poll_all(self.regions.iter_mut().map(|region| &mut region.sample), errors)
+    }
+
+    /// The first loaded region covering the note, velocity goes from 0.0 to 1.0.
+    pub fn region_for(&self, note_num: usize, velocity: f32) -> Option<&Region> {
+        let velocity = (velocity.clamp(0.0, 1.0) * 127.0).round() as u8;
+        self.regions.iter().find(|region| {
+            region.sample.data.is_some()
+                && (region.lo_key..=region.hi_key).contains(&note_num)
+                && (region.lo_vel..=region.hi_vel).contains(&velocity)
+        })
+    }
+}
+
+/// One row of a drum kit.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Pad {
+    pub name: String,
+    pub sample: SampleRef,
+    pub volume: f32,
+    pub pan: f32,
+    pub tune: f32, //in semitones
+    pub choke: u8, //pads sharing a group other than 0 cut each other off
+}
+
+impl Default for Pad {
+    fn default() -> Self {
+        Pad { name: "Pad".to_string(), sample: SampleRef::default(), volume: 1.0, pan: 0.0, tune: 0.0, choke: 0 }
+    }
+}
+
+/// Named samples played one per grid row, saved to and loaded from RON kit files.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Kit {
+    pub name: String,
+    pub pads: Vec<Pad>,
+}
+
+impl Kit {
+    /// Writes the kit, sample paths below the kit file's folder are stored relative to it.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let mut kit = self.clone();
+        for pad in kit.pads.iter_mut() {
+            if let Ok(relative) = Path::new(&pad.sample.path).strip_prefix(dir) {
+                pad.sample.path = relative.to_string_lossy().into_owned();
+            }
+        }
+        let text = ron::ser::to_string_pretty(&kit, ron::ser::PrettyConfig::default())
+            .map_err(|e| format!("{}: {}", path, e))?;
+        std::fs::write(path, text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Reads a kit file and starts loading its samples, sample paths are relative to the kit file.
+    pub fn load(path: &str) -> Result<Kit, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut kit: Kit = ron::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        for pad in kit.pads.iter_mut() {
+            pad.sample.path = dir.join(&pad.sample.path).to_string_lossy().into_owned();
+        }
+        kit.load_all();
+        Ok(kit)
+    }
+
+    pub fn load_all(&mut self) {
+        load_all(self.pads.iter_mut().map(|pad| &mut pad.sample));
+    }
+
+    pub fn poll(&mut self, errors: &mut Vec<String>) -> bool {
+        poll_all(self.pads.iter_mut().map(|pad| &mut pad.sample), errors)
+    }
+}
+
+/// Playback position within one region's or pad's sample.
+#[derive(Clone, Debug)]
+pub struct SampleVoice {
+    sample: Arc<Sample>,
+    pos: f64,
+    rate: f64,
+    loop_points: Option<(usize, usize)>,
+    pub one_shot: bool,
+    pub gain: f32,
+    pub pan: f32,
+    pub choke: u8,
+    held: bool,
+    fade: Option<f32>,
+}
+
+impl SampleVoice {
+    fn with_transpose(sample: Arc<Sample>, transpose: f64) -> SampleVoice {
+        SampleVoice {
+            rate: sample.sample_rate as f64 / SR as f64 * (transpose / 12.0).exp2(),
+            sample,
+            pos: 0.0,
+            loop_points: None,
+            one_shot: false,
+            gain: 1.0,
+            pan: 0.0,
+            choke: 0,
+            held: true,
+            fade: None,
+        }
+    }
+
+    pub fn new(region: &Region, note_num: usize) -> Option<SampleVoice> {
+        let sample = region.sample.data.clone()?;
+        let transpose = if region.pitched {note_num as f64 - region.root as f64} else {0.0};
+        Some(SampleVoice {
+            loop_points: region.loop_points.filter(|(start, end)| start < end),
+            one_shot: region.one_shot,
+            ..SampleVoice::with_transpose(sample, transpose)
+        })
+    }
+
+    /// Kit pads always play through to the end of their sample.
+    pub fn from_pad(pad: &Pad) -> Option<SampleVoice> {
+        let sample = pad.sample.data.clone()?;
+        Some(SampleVoice {
+            one_shot: true,
+            gain: pad.volume,
+            pan: pad.pan,
+            choke: pad.choke,
+            ..SampleVoice::with_transpose(sample, pad.tune as f64)
+        })
+    }
+
+    pub fn release(&mut self) {
+        self.held = false;
+    }
+
+    /// Fades the sample out quickly instead of cutting it, to avoid a click.
+    pub fn choke(&mut self) {
+        if self.fade.is_none() {
+            self.fade = Some(1.0);
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        (self.pos as usize) < self.sample.data.len() && self.fade.map_or(true, |fade| fade > 0.0)
+    }
+
+    /// Linearly interpolated sample, `pitch` transposes on top of the region in semitones.
+    pub fn next(&mut self, pitch: f32) -> f32 {
+        let data = &self.sample.data;
+        let index = self.pos as usize;
+        if index >= data.len() {
+            return 0.0;
+        }
+        let frac = (self.pos - index as f64) as f32;
+        let next = data.get(index + 1).copied().unwrap_or(0.0);
+        let value = data[index] + (next - data[index]) * frac;
+
+        self.pos += self.rate * (pitch as f64 / 12.0).exp2();
+        if let Some((start, end)) = self.loop_points {
+            if self.held && !self.one_shot && self.pos >= end as f64 {
+                self.pos -= (end - start) as f64;
+            }
+        }
+        let gain = match self.fade.as_mut() {
+            Some(fade) => {
+                *fade = (*fade - 1.0 / (CHOKE_TIME * SR as f32)).max(0.0);
+                self.gain * *fade
+            }
+            None => self.gain,
+        };
+        gain * value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(path: &str, lo_vel: u8, hi_vel: u8) -> Region {
+        let sample = SampleRef { path: path.to_string(), data: Some(Arc::new(Sample { data: vec![0.0], sample_rate: 44100 })), pending: None };
+        Region { sample, lo_key: 36, hi_key: 36, lo_vel, hi_vel, ..Region::default() }
+    }
+
+    #[test]
+    fn velocity_picks_the_layer() {
+        let sampler = Sampler { regions: vec![layer("soft", 0, 63), layer("hard", 64, 127)] };
+        let path = |note_num, velocity| sampler.region_for(note_num, velocity).map(|region| region.sample.path.as_str());
+        assert_eq!(path(36, 0.2), Some("soft"));
+        assert_eq!(path(36, 1.0), Some("hard"));
+        assert_eq!(path(36, 0.5), Some("hard"));
+        assert_eq!(path(37, 1.0), None);
+    }
+
+    #[test]
+    fn regions_still_loading_are_skipped() {
+        let mut soft = layer("soft", 0, 127);
+        soft.sample.data = None;
+        let sampler = Sampler { regions: vec![soft, layer("hard", 0, 127)] };
+        assert_eq!(sampler.region_for(36, 0.2).map(|region| region.sample.path.as_str()), Some("hard"));
+    }
+}
@@ -1,10 +1,14 @@
 use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use rodio;
-use hound;
-
-use crate::{instr::{Note, MEAS_COUNT, NOTE_COUNT}, app::Messages};
+use crate::filter::{Filter, Svf};
+use crate::fm::{FmParams, FmVoice};
+use crate::lfo::{Lfo, Modulation, Transport, LFO_COUNT};
+use crate::perc::{Drum, DrumParams, DrumVoice};
+use crate::sampler::SampleVoice;
+use crate::wavetable::{WaveParams, Wavetable};
 
 #[derive(Clone, Debug, PartialEq)]
 enum EnvelopeState {
@@ -22,6 +26,67 @@ pub enum Oscillator {
     Sawtooth,
     Pulse,
     Triangle,
+    WhiteNoise,
+    PinkNoise,
+    Wavetable,
+}
+
+/// What a track's voices play: the subtractive oscillators, the FM operators, one of the
+/// drum sounds, the track's samples, or its drum kit with one pad per grid row.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum VoiceKind {
+    Synth,
+    Fm,
+    Drum(Drum),
+    Sampler,
+    Kit,
+}
+
+impl std::fmt::Display for VoiceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoiceKind::Synth => write!(f, "Synth"),
+            VoiceKind::Fm => write!(f, "FM"),
+            VoiceKind::Drum(drum) => write!(f, "{:?}", drum),
+            VoiceKind::Sampler => write!(f, "Sampler"),
+            VoiceKind::Kit => write!(f, "Drum kit"),
+        }
+    }
+}
+
+//seed of the next noise generator, stepped so that overlapping voices don't repeat each other
+static NOISE_SEED: AtomicU32 = AtomicU32::new(0x2545_F491);
+
+/// White noise from a xorshift generator, pink noise through Paul Kellet's economy filter.
+#[derive(Clone, Debug)]
+pub struct Noise {
+    state: u32,
+    pink: [f32; 3],
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        //xorshift never leaves a zero state
+        let state = NOISE_SEED.fetch_add(0x9E37_79B9, Ordering::Relaxed) | 1;
+        Noise { state, pink: [0.0; 3] }
+    }
+}
+
+impl Noise {
+    pub fn white(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    pub fn pink(&mut self) -> f32 {
+        let white = self.white();
+        self.pink[0] = 0.99765 * self.pink[0] + white * 0.0990460;
+        self.pink[1] = 0.96300 * self.pink[1] + white * 0.2965164;
+        self.pink[2] = 0.57000 * self.pink[2] + white * 1.0526913;
+        (self.pink[0] + self.pink[1] + self.pink[2] + white * 0.1848) * 0.25
+    }
 }
 
 impl std::fmt::Display for Oscillator {
@@ -30,35 +95,184 @@ impl std::fmt::Display for Oscillator {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct MidiNote {
-    amplitude: f32,
-    freq: f32,
-    num_sample: usize,
-    state: EnvelopeState,
-    osc: Oscillator
+pub const MAX_UNISON: usize = 7;
+
+/// Stacked copies of the oscillators, detuned and spread across the stereo field.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Unison {
+    pub voices: usize,
+    pub detune: f32, //spread between the outermost voices in cents
+    pub width: f32, //0.0 keeps every voice centred, 1.0 pans the outermost hard left and right
 }
 
-pub const SR: u32 = 44100;
+impl Default for Unison {
+    fn default() -> Self {
+        Unison { voices: 1, detune: 20.0, width: 0.5 }
+    }
+}
 
-impl MidiNote {
-    #[inline]
-    pub fn new(note: usize, oscillator: Oscillator) -> MidiNote {
-        MidiNote {
-            amplitude: 0.0,
-            freq: 440.0 * ( ( (note as f32) - 69.0 ) / 12.0 ).exp2(),
-            num_sample: 0,
-            state: EnvelopeState::Attack,
-            osc: oscillator,
+/// Sine wave one or two octaves below the note.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Sub {
+    pub level: f32,
+    pub octaves: u8,
+}
+
+impl Default for Sub {
+    fn default() -> Self {
+        Sub { level: 0.0, octaves: 1 }
+    }
+}
+
+/// Duty cycle of the pulse oscillator, its own envelope sweeps it by `env_amount`.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct PulseParams {
+    pub width: f32, //share of the cycle spent high, 0.5 is a square
+    pub env_amount: f32,
+    pub envelope: Adsr,
+}
+
+impl Default for PulseParams {
+    fn default() -> Self {
+        PulseParams { width: 0.5, env_amount: 0.0, envelope: Adsr::default() }
+    }
+}
+
+/// Envelope times are full-scale ramp times in seconds, sustain is a level.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr { attack: 1.0/16.0, decay: 1.0/6.0, sustain: 0.8, release: 0.25 }
+    }
+}
+
+/// Track-wide synth parameters, individual notes can override them with `ParamLocks`.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Patch {
+    pub kind: VoiceKind,
+    pub mono: bool, //play every note on one voice
+    pub legato: bool, //mono notes overlapping the previous one glide in without re-attacking
+    pub glide: f32, //in seconds
+    pub osc: Oscillator,
+    pub osc2: Oscillator,
+    pub osc2_pitch: f32, //in semitones
+    pub osc_mix: f32, //0.0 is only the first oscillator, 1.0 only the second
+    pub unison: Unison,
+    pub sub: Sub,
+    pub pulse: PulseParams,
+    pub envelope: Adsr,
+    pub volume: f32,
+    pub pan: f32, //-1.0 is hard left, 1.0 hard right
+    pub filter: Filter,
+    pub lfos: [Lfo; LFO_COUNT],
+    pub drum: DrumParams,
+    pub wave: WaveParams,
+    pub fm: FmParams,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Patch {
+            kind: VoiceKind::Synth,
+            mono: false,
+            legato: true,
+            glide: 0.08,
+            osc: Oscillator::Sin,
+            osc2: Oscillator::Sawtooth,
+            osc2_pitch: 0.0,
+            osc_mix: 0.0,
+            unison: Unison::default(),
+            sub: Sub::default(),
+            pulse: PulseParams::default(),
+            envelope: Adsr::default(),
+            volume: 1.0,
+            pan: 0.0,
+            filter: Filter::default(),
+            lfos: [Lfo::default(); LFO_COUNT],
+            drum: DrumParams::default(),
+            wave: WaveParams::default(),
+            fm: FmParams::default(),
         }
     }
+}
 
-    pub fn get_buffer(&mut self, buffer_len: usize) -> Vec<f32> {
-        let mut buffer = vec![0_f32; buffer_len];
-        for i in 0..buffer.len() {
-            buffer[i] = self.next().unwrap();
+impl Patch {
+    pub fn uses_wavetable(&self) -> bool {
+        self.kind == VoiceKind::Synth && (self.osc == Oscillator::Wavetable || self.osc2 == Oscillator::Wavetable)
+    }
+
+    pub fn with_locks(&self, locks: &ParamLocks) -> Patch {
+        Patch {
+            kind: self.kind,
+            mono: self.mono,
+            legato: self.legato,
+            glide: self.glide,
+            osc: locks.osc.unwrap_or(self.osc),
+            osc2: self.osc2,
+            osc2_pitch: self.osc2_pitch,
+            osc_mix: self.osc_mix,
+            unison: self.unison,
+            sub: self.sub,
+            pulse: self.pulse,
+            envelope: Adsr {
+                attack: locks.attack.unwrap_or(self.envelope.attack),
+                decay: locks.decay.unwrap_or(self.envelope.decay),
+                sustain: locks.sustain.unwrap_or(self.envelope.sustain),
+                release: locks.release.unwrap_or(self.envelope.release),
+            },
+            volume: locks.volume.unwrap_or(self.volume),
+            pan: locks.pan.unwrap_or(self.pan),
+            filter: Filter {
+                cutoff: locks.cutoff.unwrap_or(self.filter.cutoff),
+                ..self.filter
+            },
+            lfos: self.lfos,
+            drum: self.drum,
+            wave: WaveParams {
+                position: locks.position.unwrap_or(self.wave.position),
+                ..self.wave
+            },
+            fm: self.fm,
         }
-        buffer
+    }
+}
+
+/// Per-note overrides of `Patch` parameters, `None` follows the track.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct ParamLocks {
+    pub osc: Option<Oscillator>,
+    pub attack: Option<f32>,
+    pub decay: Option<f32>,
+    pub sustain: Option<f32>,
+    pub release: Option<f32>,
+    pub volume: Option<f32>,
+    pub pan: Option<f32>,
+    pub cutoff: Option<f32>,
+    pub position: Option<f32>,
+}
+
+/// Linear ADSR envelope state, advanced one frame per `next`.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    level: f32,
+    state: EnvelopeState,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope { level: 0.0, state: EnvelopeState::Attack }
     }
 
     pub fn release(&mut self) {
@@ -75,47 +289,204 @@ impl MidiNote {
         self.state != EnvelopeState::Dead
     }
 
-    fn apply_envelope(&mut self) {
+    /// Pressed and not yet released.
+    pub fn is_held(&self) -> bool {
+        !matches!(self.state, EnvelopeState::Release | EnvelopeState::Dead)
+    }
+
+    pub fn next(&mut self, adsr: &Adsr) -> f32 {
+        let rate = |time: f32| 1.0/(time.max(0.0)*(SR as f32)).max(1.0);
         match self.state {
             EnvelopeState::Attack => {
-                self.amplitude += 16.0/(SR as f32);
-                if self.amplitude >= 1.0 {
-                    self.amplitude = 1.0;
+                self.level += rate(adsr.attack);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
                     self.state = EnvelopeState::Decay;
                 }
             }
             EnvelopeState::Decay => {
-                self.amplitude -= 6.0/(SR as f32);
-                if self.amplitude <= 0.8 {
-                    self.amplitude = 0.8;
+                self.level -= rate(adsr.decay);
+                if self.level <= adsr.sustain {
+                    self.level = adsr.sustain;
                     self.state = EnvelopeState::Sustain;
                 }
             }
             EnvelopeState::Sustain => {}
             EnvelopeState::ToBeReleased => {
-                self.amplitude += 16.0/(SR as f32);
-                if self.amplitude >= 1.0 {
-                    self.amplitude = 1.0;
+                self.level += rate(adsr.attack);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
                     self.state = EnvelopeState::Release;
                 }
             }
             EnvelopeState::Release => {
-                self.amplitude -= 4.0/(SR as f32);
-                if self.amplitude <= 0.0 {
-                    self.amplitude = 0.0;
+                self.level -= rate(adsr.release);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
                     self.state = EnvelopeState::Dead;
                 }
             }
             EnvelopeState::Dead => {}
         }
+        self.level
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MidiNote {
+    note: usize,
+    freq: f32,
+    target_freq: f32,
+    glide_ratio: f32, //applied to `freq` every frame until it reaches `target_freq`
+    phases: [[f32; 2]; MAX_UNISON], //both oscillators of every unison voice
+    sub_phase: f32,
+    transport: Transport,
+    right: Option<f32>,
+    envelope: Envelope,
+    filter_envelope: Envelope,
+    wave_envelope: Envelope,
+    pulse_envelope: Envelope,
+    wavetable: Option<Arc<Wavetable>>,
+    svf: Svf,
+    svf_right: Svf,
+    noise: Noise,
+    drum: DrumVoice,
+    fm: FmVoice,
+    sample: Option<SampleVoice>,
+    patch: Patch,
+    locks: ParamLocks,
+    velocity: f32,
+}
+
+pub const SR: u32 = 44100;
+pub const CHANNELS: u16 = 2;
+
+impl MidiNote {
+    #[inline]
+    pub fn new(note: usize, patch: &Patch) -> MidiNote {
+        MidiNote {
+            note,
+            freq: note_freq(note),
+            target_freq: note_freq(note),
+            glide_ratio: 1.0,
+            //unison voices start out of phase so they don't all peak together
+            phases: std::array::from_fn(|i| [(i as f32 * 2.4) % (2.0 * PI); 2]),
+            sub_phase: 0.0,
+            transport: Transport::default(),
+            right: None,
+            envelope: Envelope::new(),
+            filter_envelope: Envelope::new(),
+            wave_envelope: Envelope::new(),
+            pulse_envelope: Envelope::new(),
+            wavetable: None,
+            svf: Svf::default(),
+            svf_right: Svf::default(),
+            noise: Noise::default(),
+            drum: DrumVoice::new(),
+            fm: FmVoice::new(),
+            sample: None,
+            patch: *patch,
+            locks: ParamLocks::default(),
+            velocity: 1.0,
+        }
+    }
+
+    pub fn get_buffer(&mut self, buffer_len: usize) -> Vec<f32> {
+        self.take(buffer_len).collect()
+    }
+
+    pub fn release(&mut self) {
+        self.envelope.release();
+        self.filter_envelope.release();
+        self.wave_envelope.release();
+        self.pulse_envelope.release();
+        self.fm.release();
+        if let Some(sample) = self.sample.as_mut() {
+            sample.release();
+        }
+    }
+
+    pub fn press(&mut self) {
+        self.envelope.press();
+        self.filter_envelope.press();
+        self.wave_envelope.press();
+        self.pulse_envelope.press();
+        self.drum.trigger();
+        self.fm.press();
+    }
+
+    /// Lines the voice's LFOs up with the sequencer before rendering from `transport.frame`.
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    pub fn note(&self) -> usize {
+        self.note
+    }
+
+    #[cfg(test)]
+    pub fn freq(&self) -> f32 {
+        self.freq
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.envelope.is_held()
+    }
+
+    /// Moves the voice to another note, sliding there exponentially over `time` seconds.
+    pub fn glide_to(&mut self, note: usize, time: f32) {
+        self.note = note;
+        self.target_freq = note_freq(note);
+        let frames = time * SR as f32;
+        if frames < 1.0 {
+            self.freq = self.target_freq;
+        }
+        else {
+            self.glide_ratio = (self.target_freq / self.freq).powf(1.0 / frames);
+        }
+    }
+
+    pub fn set_velocity(&mut self, velocity: f32) {
+        self.velocity = velocity;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        match self.patch.kind {
+            VoiceKind::Synth => self.envelope.is_alive(),
+            VoiceKind::Fm => self.fm.is_alive(&self.patch.fm),
+            VoiceKind::Drum(_) => self.drum.is_alive(),
+            VoiceKind::Sampler | VoiceKind::Kit => self.sample.as_ref().map_or(false, |sample| {
+                sample.is_alive() && (sample.one_shot || self.envelope.is_alive())
+            }),
+        }
+    }
+
+    /// Restarts sample playback, with the region or pad picked for the pressed note.
+    pub fn set_sample(&mut self, sample: Option<SampleVoice>) {
+        self.sample = sample;
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: &Arc<Wavetable>) {
+        self.wavetable = Some(wavetable.clone());
+    }
+
+    /// Fades out a kit pad if it belongs to `group`.
+    pub fn choke(&mut self, group: u8) {
+        if let Some(sample) = self.sample.as_mut() {
+            if group != 0 && sample.choke == group {
+                sample.choke();
+            }
+        }
     }
 
     fn sawtooth(&self, x: f32) -> f32 {
         ((x%(2.0*PI))-PI)/PI
     }
         
-    fn oscillator(&self, x: f32) -> f32 {
-        match self.osc {
+    /// `position` only matters to the wavetable, which reads the frame it points at, and
+    /// `width` only to the pulse.
+    fn oscillator(&mut self, osc: Oscillator, x: f32, position: f32, width: f32) -> f32 {
+        match osc {
             Oscillator::Sin => {
                 x.sin()
             }
@@ -123,17 +494,65 @@ impl MidiNote {
                 self.sawtooth(x)
             }
             Oscillator::Pulse => {
-                self.sawtooth(x).signum()
+                if x < 2.0 * PI * width {1.0} else {-1.0}
             }
             Oscillator::Triangle => {
                 1.0 - 2.0*self.sawtooth(x).abs()
             }
+            Oscillator::WhiteNoise => {
+                self.noise.white()
+            }
+            Oscillator::PinkNoise => {
+                self.noise.pink()
+            }
+            Oscillator::Wavetable => {
+                self.wavetable.as_ref().map_or(0.0, |wavetable| wavetable.sample(position, x / (2.0 * PI)))
+            }
+        }
+    }
+
+    /// Both oscillators of every unison voice plus the sub, as a left/right pair.
+    fn synth_frame(&mut self, freq: f32, position: f32, width: f32) -> (f32, f32) {
+        let unison = self.patch.unison;
+        let voices = unison.voices.clamp(1, MAX_UNISON);
+        let osc2_ratio = (self.patch.osc2_pitch / 12.0).exp2();
+        let mix = self.patch.osc_mix.clamp(0.0, 1.0);
+        let (mut left, mut right) = (0.0, 0.0);
+        for voice in 0..voices {
+            //-1.0 to 1.0 across the stack
+            let spread = if voices > 1 {2.0 * voice as f32 / (voices - 1) as f32 - 1.0} else {0.0};
+            let freq = freq * (unison.detune * spread / 2400.0).exp2();
+            let [phase1, phase2] = self.phases[voice];
+            let mut value = 0.0;
+            if mix < 1.0 {
+                value += (1.0 - mix) * self.oscillator(self.patch.osc, phase1, position, width);
+            }
+            if mix > 0.0 {
+                value += mix * self.oscillator(self.patch.osc2, phase2, position, width);
+            }
+            self.phases[voice] = [
+                (phase1 + 2.0 * PI * freq / SR as f32) % (2.0 * PI),
+                (phase2 + 2.0 * PI * freq * osc2_ratio / SR as f32) % (2.0 * PI),
+            ];
+            let side = unison.width.clamp(0.0, 1.0) * spread;
+            left += value * (1.0 - side).min(1.0);
+            right += value * (1.0 + side).min(1.0);
         }
+        let sub = self.patch.sub.level * self.sub_phase.sin();
+        let sub_freq = freq / (self.patch.sub.octaves.clamp(1, 2) as f32).exp2();
+        self.sub_phase = (self.sub_phase + 2.0 * PI * sub_freq / SR as f32) % (2.0 * PI);
+        (left / voices as f32 + sub, right / voices as f32 + sub)
     }
 
-    pub fn set_oscillator(&mut self, oscillator: &Oscillator) {
-        self.osc = *oscillator;
-    } 
+    /// Applies the track patch, keeping whatever the last pressed note locked.
+    pub fn set_patch(&mut self, patch: &Patch) {
+        self.patch = patch.with_locks(&self.locks);
+    }
+
+    pub fn set_locks(&mut self, patch: &Patch, locks: &ParamLocks) {
+        self.locks = *locks;
+        self.set_patch(patch);
+    }
 
 }
 
@@ -142,12 +561,60 @@ impl Iterator for MidiNote {
 
     #[inline]
     fn next(&mut self) -> Option<f32> {
-        self.num_sample = self.num_sample.wrapping_add(1);
-        
-        self.apply_envelope();
+        //samples are interleaved left/right, the right one is computed along with the left
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let mut modulation = Modulation::default();
+        for lfo in self.patch.lfos.iter() {
+            lfo.modulate(&mut modulation, &self.transport);
+        }
+        self.transport.frame += 1;
+
+        if self.freq != self.target_freq {
+            self.freq *= self.glide_ratio;
+            if (self.glide_ratio > 1.0) == (self.freq > self.target_freq) {
+                self.freq = self.target_freq;
+            }
+        }
 
-        let value = 2.0 * PI * self.freq * self.num_sample as f32 / (SR as f32);
-        Some(self.amplitude * self.oscillator(value))
+        let freq = self.freq * (modulation.pitch / 12.0).exp2();
+
+        //FM operators, drums and one-shot samples shape their own amplitude
+        let (source, amplitude) = match self.patch.kind {
+            VoiceKind::Synth => {
+                let wave = &self.patch.wave;
+                let position = wave.position + wave.env_amount * self.wave_envelope.next(&wave.envelope) + modulation.position;
+                let pulse = &self.patch.pulse;
+                let width = pulse.width + pulse.env_amount * self.pulse_envelope.next(&pulse.envelope) + modulation.width;
+                //keep a sliver of the cycle on each side so the pulse never goes silent
+                let width = width.clamp(0.02, 0.98);
+                (self.synth_frame(freq, position, width), self.envelope.next(&self.patch.envelope))
+            }
+            VoiceKind::Fm => (mono(self.fm.next(&self.patch.fm, freq)), 1.0),
+            VoiceKind::Drum(drum) => (mono(self.drum.next(drum, &self.patch.drum, freq)), 1.0),
+            VoiceKind::Sampler | VoiceKind::Kit => match self.sample.as_mut() {
+                Some(sample) => {
+                    let amplitude = if sample.one_shot {1.0} else {self.envelope.next(&self.patch.envelope)};
+                    (mono(sample.next(modulation.pitch)), amplitude)
+                }
+                None => ((0.0, 0.0), 0.0),
+            },
+        };
+        let amplitude = amplitude * (1.0 + modulation.amplitude);
+        let filter = &self.patch.filter;
+        let cutoff = filter.cutoff 
+            * (filter.env_amount * self.filter_envelope.next(&filter.envelope) + modulation.cutoff).exp2();
+
+        let gain = self.patch.volume * self.velocity * amplitude;
+        let left = gain * self.svf.process(source.0, filter.mode, cutoff, filter.resonance);
+        let right = gain * self.svf_right.process(source.1, filter.mode, cutoff, filter.resonance);
+        let sample_pan = self.sample.as_ref().map_or(0.0, |sample| sample.pan);
+        let pan_position = self.patch.pan + sample_pan + modulation.pan;
+        let (left, _) = pan(left, pan_position);
+        let (_, right) = pan(right, pan_position);
+        self.right = Some(right);
+        Some(left)
     }
 }
 
@@ -159,7 +626,7 @@ impl rodio::Source for MidiNote {
 
     #[inline]
     fn channels(&self) -> u16 {
-        1
+        CHANNELS
     }
 
     #[inline]
@@ -173,118 +640,35 @@ impl rodio::Source for MidiNote {
     }
 }
 
+fn note_freq(note: usize) -> f32 {
+    440.0 * ( ( (note as f32) - 69.0 ) / 12.0 ).exp2()
+}
+
+fn mono(sample: f32) -> (f32, f32) {
+    (sample, sample)
+}
 
-pub fn process_audio (
-    rx: std::sync::mpsc::Receiver<Messages>,
-) {
-    let (_stream, stream_handle) = 
-        rodio::OutputStream::try_default().expect("Could not get output device.");
-    let sink = rodio::Sink::try_new(&stream_handle).expect("Could not get output device.");    
-    let mut midi_notes: std::collections::HashMap<usize, MidiNote> = std::collections::HashMap::new();
-
-    let mut instr_state = vec![vec![Note::default(); MEAS_COUNT]; NOTE_COUNT];
-    let mut tempo = 60;
-    let mut clock = 0;
-
-    let mut recording = false;
-    let mut recorded_data: Vec<f32> = vec![];
-
-    let mut osc = Oscillator::Sin;
-    
-    let mut active = false; 
-    loop {
-        if let Ok(msg) = rx.try_recv() {
-            match msg {
-                Messages::Play(changed_notes) => {
-                    for (new_note, i, j) in changed_notes {
-                        instr_state[i][j] = new_note;
-                    }
-                    active = true;
-                    sink.play();
-                }
-                Messages::Stop => {
-                    active = false;
-                    if recording {
-                        export_wav(recorded_data.clone());
-                        recorded_data.clear();
-                    }
-                    //reset instrument
-                    for row in instr_state.iter_mut() {
-                        for note in row.iter_mut() {
-                            *note = Note::default();
-                        }
-                    }
-                    clock = 0;
-                }
-                Messages::Record => {
-                    recording = true;
-                }
-                Messages::Tempo(new_tempo) => {
-                    tempo = new_tempo;
-                }
-                Messages::Oscillator(new_osc) => {
-                    osc = new_osc;
-                    for note in midi_notes.values_mut() {
-                        note.set_oscillator(&osc);
-                    }
-                }
-            }
-        }
-    
-        if !active || sink.len() > 2 {continue;}
-
-        for ind in 0..NOTE_COUNT {
-            let note = instr_state[ind][clock];
-            let note_num = ind + 21;
-            if note.duration != 0 {
-                match midi_notes.get_mut(&note_num) {
-                    Some(midi_note) => {
-                        midi_note.press();
-                    }
-                    None => {
-                        midi_notes.insert(note_num, MidiNote::new(note_num, osc.clone()));
-                    }
-                }
-            }
-            if let Some(start) = note.starts_at {
-                if instr_state[ind][start].duration + start - 1 == clock{
-                    if let Some(midi_note) = midi_notes.get_mut(&note_num) {
-                        midi_note.release();
-                    }
-                }
-            }
-        }
-        let mut counter = 0.0;
-        let buffer_len = (SR as f32 * 0.125 * 60.0/tempo as f32).round() as usize;
-        let mut data = vec![0_f32; buffer_len];
-        for midi_note in midi_notes.values_mut() {
-            if midi_note.is_alive() {
-                data.iter_mut().zip(midi_note.get_buffer(buffer_len).iter()).for_each(|(d, b)| {
-                    *d += b;
-                });
-                counter += 1.0;
-            }
-        }
-        data.iter_mut().for_each(|d| {
-            *d /= counter;
-        });
+/// Equal-power pan law, a centred signal is 3 dB down in each channel.
+pub fn pan(sample: f32, pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+    (sample * angle.cos(), sample * angle.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if recording {recorded_data.extend_from_slice(&data);}
-        sink.append(rodio::buffer::SamplesBuffer::new(1, SR, data));
-        clock = (clock + 1) % MEAS_COUNT;
+    #[test]
+    fn pan_keeps_the_power_constant() {
+        for i in 0..=20 {
+            let (left, right) = pan(1.0, i as f32 / 10.0 - 1.0);
+            assert!((left * left + right * right - 1.0).abs() < 1.0e-6);
+        }
+        let (left, right) = pan(1.0, 0.0);
+        assert!((left - right).abs() < 1.0e-6);
+        assert!((20.0 * left.log10() + 3.01).abs() < 0.01);
+        assert_eq!(pan(1.0, -2.0), pan(1.0, -1.0));
+        let (left, right) = pan(1.0, 1.0);
+        assert!(left.abs() < 1.0e-6 && (right - 1.0).abs() < 1.0e-6);
     }
 }
-
-fn export_wav(data: Vec<f32>) {
-    let spec = hound::WavSpec {
-        channels: 1,
-        sample_rate: 44100,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Float,
-    };
-    let mut writer = hound::WavWriter::create("step_sequencer_recording.wav", spec).unwrap();
-    for datum in data {
-        writer.write_sample(datum).unwrap();
-    }
-    writer.finalize().unwrap();
-}
\ No newline at end of file
@@ -0,0 +1,144 @@
+use std::f32::consts::PI;
+
+use crate::sampler::SampleRef;
+use crate::synth::Adsr;
+
+/// Length every frame is resampled to.
+pub const TABLE_SIZE: usize = 512;
+//frame length of multi-frame wavetable files, as written by most wavetable synths
+const FILE_FRAME_SIZE: usize = 2048;
+
+/// Single-cycle waveforms the wavetable oscillator morphs through. The frames are saved
+/// with the project since they can be drawn by hand.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct Wavetable {
+    pub frames: Vec<Vec<f32>>,
+    //a file being decoded in the background, cut into frames once it arrives
+    #[serde(skip)]
+    pub file: SampleRef,
+}
+
+impl Default for Wavetable {
+    fn default() -> Self {
+        let sine = (0..TABLE_SIZE).map(|i| (2.0 * PI * i as f32 / TABLE_SIZE as f32).sin()).collect();
+        Wavetable { frames: vec![sine], file: SampleRef::default() }
+    }
+}
+
+impl Wavetable {
+    /// Starts decoding a WAV file on a background thread, `poll` replaces the frames with it.
+    pub fn load(&mut self, path: &str) {
+        self.file = SampleRef::new(path.to_string());
+        self.file.load();
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.file.is_loading()
+    }
+
+    /// Takes the frames of a finished load, adding a failure to `errors`. Returns whether
+    /// the frames changed.
+    pub fn poll(&mut self, errors: &mut Vec<String>) -> bool {
+        let Some(result) = self.file.poll() else {return false};
+        //the decoded audio is only needed to cut the frames from
+        let sample = self.file.data.take();
+        match result.and_then(|()| frames(&self.file.path, sample.as_deref().map_or(&[], |sample| &sample.data))) {
+            Ok(frames) => {
+                self.frames = frames;
+                true
+            }
+            Err(error) => {
+                errors.push(error);
+                false
+            }
+        }
+    }
+
+    /// `position` crossfades from the first frame at 0.0 to the last at 1.0, `phase` runs
+    /// from 0.0 to 1.0 over a cycle.
+    pub fn sample(&self, position: f32, phase: f32) -> f32 {
+        if self.frames.is_empty() {
+            return 0.0;
+        }
+        let position = position.clamp(0.0, 1.0) * (self.frames.len() - 1) as f32;
+        let index = position as usize;
+        let next = (index + 1).min(self.frames.len() - 1);
+        let a = read(&self.frames[index], phase);
+        let b = read(&self.frames[next], phase);
+        a + (b - a) * position.fract()
+    }
+}
+
+/// Reads a file either as one cycle, or as consecutive 2048 sample frames when its length
+/// is a multiple of that.
+fn frames(path: &str, data: &[f32]) -> Result<Vec<Vec<f32>>, String> {
+    if data.is_empty() {
+        return Err(format!("{}: no audio", path));
+    }
+    let frame_size = if data.len() > FILE_FRAME_SIZE && data.len() % FILE_FRAME_SIZE == 0 {FILE_FRAME_SIZE} else {data.len()};
+    let peak = data.iter().fold(0.0f32, |peak, x| peak.max(x.abs())).max(1.0e-6);
+    Ok(data.chunks(frame_size)
+        .map(|frame| (0..TABLE_SIZE).map(|i| read(frame, i as f32 / TABLE_SIZE as f32) / peak).collect())
+        .collect())
+}
+
+/// Linearly interpolated, wrapping lookup into one cycle.
+fn read(frame: &[f32], phase: f32) -> f32 {
+    if frame.is_empty() {
+        return 0.0;
+    }
+    let pos = phase.rem_euclid(1.0) * frame.len() as f32;
+    let index = pos as usize % frame.len();
+    let next = (index + 1) % frame.len();
+    frame[index] + (frame[next] - frame[index]) * pos.fract()
+}
+
+/// Where the wavetable oscillator reads, its own envelope sweeps the position by `env_amount`.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(default)]
+pub struct WaveParams {
+    pub position: f32,
+    pub env_amount: f32,
+    pub envelope: Adsr,
+}
+
+impl Default for WaveParams {
+    fn default() -> Self {
+        WaveParams { position: 0.0, env_amount: 0.0, envelope: Adsr::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_interpolates_within_and_between_frames() {
+        let wavetable = Wavetable { frames: vec![vec![0.0, 1.0], vec![1.0, 1.0]], ..Wavetable::default() };
+        assert_eq!(wavetable.sample(0.0, 0.25), 0.5);
+        assert_eq!(wavetable.sample(0.0, 0.75), 0.5); //wraps back to the start of the cycle
+        assert_eq!(wavetable.sample(0.5, 0.0), 0.5);
+        assert_eq!(wavetable.sample(1.0, 0.0), 1.0);
+        assert_eq!(wavetable.sample(2.0, 0.0), 1.0);
+        assert_eq!(Wavetable { frames: vec![], ..Wavetable::default() }.sample(0.5, 0.5), 0.0);
+    }
+
+    #[test]
+    fn default_table_is_a_sine() {
+        let wavetable = Wavetable::default();
+        assert!((wavetable.sample(0.0, 0.25) - 1.0).abs() < 1.0e-4);
+        assert!(wavetable.sample(0.0, 0.5).abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn files_split_into_normalized_frames() {
+        let data: Vec<f32> = (0..2 * FILE_FRAME_SIZE).map(|i| if i < FILE_FRAME_SIZE {0.5} else {-0.25}).collect();
+        let table = frames("table.wav", &data).unwrap();
+        assert_eq!(table.len(), 2);
+        assert!(table.iter().all(|frame| frame.len() == TABLE_SIZE));
+        assert_eq!((table[0][0], table[1][0]), (1.0, -0.5));
+        assert_eq!(frames("cycle.wav", &data[..100]).unwrap().len(), 1);
+        assert!(frames("empty.wav", &[]).is_err());
+    }
+}